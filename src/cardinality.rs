@@ -0,0 +1,154 @@
+use std::collections::{HashMap, HashSet};
+
+use clap::ValueEnum;
+
+use crate::point::{Point, Value};
+
+/// What to do with a tag that has a new value once `--max-tag-values` is reached for its key.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum CardinalityAction {
+    /// Write the tag anyway, after warning once for the tag key.
+    Warn,
+    /// Leave the tag out of the point.
+    Drop,
+}
+
+/// Keeps track of the distinct values seen for every tag key, as every new value of a tag
+/// creates another series in InfluxDB.
+pub struct CardinalityGuard {
+    max_values: usize,
+    action: CardinalityAction,
+    /// At most `max_values` values for every tag key.
+    seen: HashMap<String, HashSet<String>>,
+    /// Tag keys that reached `max_values` and were warned about.
+    exceeded: HashSet<String>,
+}
+
+fn tag_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.to_string(),
+        Value::Integer(i) => i.to_string(),
+        Value::UnsignedInteger(u) => u.to_string(),
+        Value::Float(f) => f.to_string(),
+        Value::Boolean(b) => b.to_string(),
+    }
+}
+
+impl CardinalityGuard {
+    pub fn new(max_values: usize, action: CardinalityAction) -> CardinalityGuard {
+        CardinalityGuard {
+            max_values,
+            action,
+            seen: HashMap::new(),
+            exceeded: HashSet::new(),
+        }
+    }
+
+    /// Record the tag values of `point`, leaving out tags with new values beyond the limit when
+    /// the action is `Drop`.
+    pub fn check<'a>(&mut self, mut point: Point<'a>) -> Point<'a> {
+        point.tags.retain(|key, value| {
+            let value = tag_value(value);
+            let seen = self.seen.entry(key.clone()).or_default();
+            if seen.contains(&value) {
+                return true;
+            }
+            if seen.len() < self.max_values {
+                seen.insert(value);
+                return true;
+            }
+
+            if self.exceeded.insert(key.clone()) {
+                log::warn!(
+                    "Tag {:?} of measurement {} has more than {} distinct values",
+                    key,
+                    point.measurement,
+                    self.max_values
+                );
+            }
+            match self.action {
+                CardinalityAction::Warn => true,
+                CardinalityAction::Drop => {
+                    log::debug!("Dropping tag {}={:?}", key, value);
+                    false
+                }
+            }
+        });
+        point
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(tags: &[(&str, &str)]) -> Point<'static> {
+        tags.iter().fold(Point::new("m"), |point, (key, value)| {
+            point.add_tag(*key, Value::String(value.to_string().into()))
+        })
+    }
+
+    fn host(point: &Point) -> Option<String> {
+        point.tags.get("host").map(tag_value)
+    }
+
+    #[test]
+    fn values_up_to_the_limit_are_kept() {
+        for action in [CardinalityAction::Warn, CardinalityAction::Drop] {
+            let mut guard = CardinalityGuard::new(2, action);
+            assert_eq!(
+                host(&guard.check(point(&[("host", "a")]))),
+                Some("a".to_owned())
+            );
+            assert_eq!(
+                host(&guard.check(point(&[("host", "b")]))),
+                Some("b".to_owned())
+            );
+            assert!(guard.exceeded.is_empty());
+        }
+    }
+
+    #[test]
+    fn new_values_past_the_limit() {
+        let mut warn = CardinalityGuard::new(1, CardinalityAction::Warn);
+        let mut drop = CardinalityGuard::new(1, CardinalityAction::Drop);
+        for guard in [&mut warn, &mut drop] {
+            guard.check(point(&[("host", "a")]));
+        }
+
+        assert_eq!(
+            host(&warn.check(point(&[("host", "b")]))),
+            Some("b".to_owned())
+        );
+        let point = drop.check(point(&[("host", "b"), ("site", "home")]));
+        assert_eq!(host(&point), None);
+        // Other tags of the point are not affected.
+        assert!(point.tags.contains_key("site"));
+    }
+
+    #[test]
+    fn seen_values_pass_after_the_limit() {
+        let mut guard = CardinalityGuard::new(1, CardinalityAction::Drop);
+        guard.check(point(&[("host", "a")]));
+        guard.check(point(&[("host", "b")]));
+        assert_eq!(
+            host(&guard.check(point(&[("host", "a")]))),
+            Some("a".to_owned())
+        );
+        assert_eq!(host(&guard.check(point(&[("host", "b")]))), None);
+    }
+
+    #[test]
+    fn warned_once_per_key() {
+        let mut guard = CardinalityGuard::new(1, CardinalityAction::Warn);
+        for value in ["a", "b", "c", "d"] {
+            guard.check(point(&[("host", value), ("site", "home")]));
+        }
+        assert_eq!(guard.exceeded, HashSet::from(["host".to_owned()]));
+        // The values past the limit are not remembered, so the memory used stays bounded.
+        assert_eq!(guard.seen["host"].len(), 1);
+
+        guard.check(point(&[("site", "work")]));
+        assert_eq!(guard.exceeded.len(), 2);
+    }
+}
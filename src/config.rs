@@ -1,16 +1,265 @@
-use influxdb_rs::{Point, Value as DBValue};
-use serde::Deserialize;
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fmt,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{
+    alphabet,
+    engine::{
+        general_purpose::{GeneralPurpose, PAD},
+        DecodePaddingMode,
+    },
+    prelude::BASE64_STANDARD,
+    Engine,
+};
+use influxdb_rs::Precision;
+use regex::Regex;
+use rumqttc::QoS;
+use serde::{de::Error as _, Deserialize, Deserializer};
 use serde_json::Value;
+use url::Url;
 
-#[derive(Debug, Deserialize)]
+use crate::{
+    hex,
+    jsonpath::{JsonPath, Op},
+    mqtt::Message,
+    msgpack,
+    point::{Point, Value as DBValue},
+    sink::WriteTarget,
+};
+
+/// Time allowed to fetch the configuration from a URL.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Failure to load the mapping configuration file.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file could not be opened or read.
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// The configuration could not be fetched from a URL, or the server responded with an error.
+    Fetch {
+        path: PathBuf,
+        source: reqwest::Error,
+    },
+    /// The file is not valid YAML, or does not match the configuration schema.
+    Syntax {
+        path: PathBuf,
+        location: Option<serde_yaml::Location>,
+        source: serde_yaml::Error,
+    },
+    /// The file parsed, but its contents do not make sense.
+    Invalid { path: PathBuf, reason: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => {
+                write!(f, "{}: failed to read file: {}", path.display(), source)
+            }
+            ConfigError::Fetch { path, source } => {
+                write!(f, "{}: failed to fetch: {}", path.display(), source)
+            }
+            ConfigError::Syntax {
+                path,
+                location: Some(location),
+                source,
+            } => write!(
+                f,
+                "{}:{}:{}: {}",
+                path.display(),
+                location.line(),
+                location.column(),
+                source
+            ),
+            ConfigError::Syntax {
+                path,
+                location: None,
+                source,
+            } => write!(f, "{}: {}", path.display(), source),
+            ConfigError::Invalid { path, reason } => write!(f, "{}: {}", path.display(), reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io { source, .. } => Some(source),
+            ConfigError::Fetch { source, .. } => Some(source),
+            ConfigError::Syntax { source, .. } => Some(source),
+            ConfigError::Invalid { .. } => None,
+        }
+    }
+}
+
+/// Failure to turn a received payload into a point.
+#[derive(Debug)]
+pub enum ExtractError {
+    /// A text payload is not valid UTF-8.
+    InvalidUtf8 { len: usize },
+    /// A JSON payload could not be parsed.
+    InvalidJson {
+        source: serde_json::Error,
+        preview: String,
+    },
+    /// A MessagePack payload could not be decoded.
+    InvalidMsgPack { reason: String, preview: String },
+    /// A hex payload of a `binary` entry could not be decoded.
+    InvalidHex { reason: String, preview: String },
+    /// A payload could not be decoded according to the `payload_encoding` of its entry.
+    InvalidEncoding {
+        encoding: PayloadEncoding,
+        reason: String,
+        preview: String,
+    },
+    /// A binary payload ends before a field of a `binary` entry.
+    TooShort { field: String, len: usize },
+    /// A field resolved to `null` and its `on_null` policy is `Error`.
+    NullValue { src_path: String },
+    /// The timestamp path did not resolve to a value.
+    TimestampMissing { src_path: String },
+    /// The timestamp value could not be parsed in the configured format.
+    TimestampInvalid { value: String },
+    /// A value could not be converted to the configured `dst_type`.
+    Coerce {
+        field: String,
+        dst_type: DstType,
+        value: String,
+    },
+    /// A value with a scale or offset configured is not a number.
+    NotNumeric { field: String, value: String },
+    /// An element of an array is not a number, but the `array` mode of its field sums or
+    /// averages them.
+    NotNumericArray { field: String, value: String },
+    /// A value is missing from its map and its `on_unmapped` policy is `Error`.
+    Unmapped { field: String, value: String },
+    /// The `src_path` of a `json_array` entry, or the payload itself without one, is not an
+    /// array.
+    NotArray { src_path: Option<String> },
+    /// A float field is NaN or infinite and the `on_non_finite` policy is `Error`.
+    NonFinite { field: String, value: f64 },
+}
+
+impl fmt::Display for ExtractError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExtractError::InvalidUtf8 { len } => {
+                write!(f, "payload of {} bytes is not valid UTF-8", len)
+            }
+            ExtractError::InvalidJson { source, preview } => {
+                write!(f, "invalid JSON ({}) in payload {:?}", source, preview)
+            }
+            ExtractError::InvalidMsgPack { reason, preview } => {
+                write!(
+                    f,
+                    "invalid MessagePack ({}) in payload {:?}",
+                    reason, preview
+                )
+            }
+            ExtractError::InvalidHex { reason, preview } => {
+                write!(f, "invalid hex ({}) in payload {:?}", reason, preview)
+            }
+            ExtractError::InvalidEncoding {
+                encoding,
+                reason,
+                preview,
+            } => write!(
+                f,
+                "invalid {} ({}) in payload {:?}",
+                encoding, reason, preview
+            ),
+            ExtractError::TooShort { field, len } => {
+                write!(f, "payload of {} bytes ends before field {:?}", len, field)
+            }
+            ExtractError::NullValue { src_path } => {
+                write!(f, "field {:?} resolved to null", src_path)
+            }
+            ExtractError::TimestampMissing { src_path } => {
+                write!(f, "timestamp {:?} not found", src_path)
+            }
+            ExtractError::TimestampInvalid { value } => {
+                write!(f, "timestamp {} could not be parsed", value)
+            }
+            ExtractError::Coerce {
+                field,
+                dst_type,
+                value,
+            } => write!(
+                f,
+                "field {:?} with value {} cannot be converted to {:?}",
+                field, value, dst_type
+            ),
+            ExtractError::NotNumeric { field, value } => write!(
+                f,
+                "field {:?} with value {} is not a number, but has a scale or offset",
+                field, value
+            ),
+            ExtractError::NotNumericArray { field, value } => write!(
+                f,
+                "field {:?} has element {}, which is not a number",
+                field, value
+            ),
+            ExtractError::Unmapped { field, value } => {
+                write!(f, "field {:?} with value {} is not mapped", field, value)
+            }
+            ExtractError::NotArray { src_path } => match src_path {
+                Some(src_path) => write!(f, "{:?} is not an array", src_path),
+                None => f.write_str("payload is not an array"),
+            },
+            ExtractError::NonFinite { field, value } => {
+                write!(
+                    f,
+                    "field {:?} is {}, which InfluxDB does not accept",
+                    field, value
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ExtractError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ExtractError::InvalidJson { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Start of a payload as text, for use in log messages.
+pub fn preview(payload: &[u8]) -> String {
+    const MAX_LEN: usize = 64;
+
+    let text = String::from_utf8_lossy(&payload[..payload.len().min(MAX_LEN)]);
+    if payload.len() > MAX_LEN {
+        format!("{}...", text)
+    } else {
+        text.into_owned()
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
 pub enum DstVariant {
+    #[default]
     Field,
     Tag,
 }
 
-impl Default for DstVariant {
-    fn default() -> Self {
-        Self::Field
+impl fmt::Display for DstVariant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DstVariant::Field => f.write_str("field"),
+            DstVariant::Tag => f.write_str("tag"),
+        }
     }
 }
 
@@ -23,17 +272,548 @@ impl DstVariant {
     }
 }
 
+/// The InfluxDB type to convert an extracted value to, instead of inferring it.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub enum DstType {
+    Float,
+    Integer,
+    /// Unsigned integer, for counters that may exceed the range of `Integer`. Only supported by
+    /// InfluxDB 2.
+    Unsigned,
+    /// Accepts booleans, the numbers `0` and `1`, and the strings `true`/`false`, `on`/`off`,
+    /// `yes`/`no` and `1`/`0` in any case.
+    Boolean,
+    String,
+}
+
+impl DstType {
+    /// The type of a value.
+    fn of(value: &DBValue) -> DstType {
+        match value {
+            DBValue::String(_) => DstType::String,
+            DBValue::Integer(_) => DstType::Integer,
+            DBValue::UnsignedInteger(_) => DstType::Unsigned,
+            DBValue::Float(_) => DstType::Float,
+            DBValue::Boolean(_) => DstType::Boolean,
+        }
+    }
+
+    /// Convert a JSON value, or `None` if it cannot be represented as this type.
+    pub fn coerce(&self, value: &Value) -> Option<DBValue<'static>> {
+        Some(match (self, value) {
+            (DstType::Float, Value::Number(n)) => DBValue::Float(n.as_f64()?),
+            (DstType::Float, Value::String(s)) => DBValue::Float(s.trim().parse().ok()?),
+            (DstType::Float, Value::Bool(b)) => DBValue::Float(if *b { 1.0 } else { 0.0 }),
+            (DstType::Integer, Value::Number(n)) => DBValue::Integer(match n.as_i64() {
+                Some(i) => i,
+                None => whole(n.as_f64()?)?,
+            }),
+            (DstType::Integer, Value::String(s)) => {
+                let s = s.trim();
+                DBValue::Integer(match s.parse() {
+                    Ok(i) => i,
+                    Err(_) => whole(s.parse().ok()?)?,
+                })
+            }
+            (DstType::Integer, Value::Bool(b)) => DBValue::Integer(i64::from(*b)),
+            (DstType::Unsigned, Value::Number(n)) => DBValue::UnsignedInteger(match n.as_u64() {
+                Some(u) => u,
+                None => whole_unsigned(n.as_f64()?)?,
+            }),
+            (DstType::Unsigned, Value::String(s)) => {
+                let s = s.trim();
+                DBValue::UnsignedInteger(match s.parse() {
+                    Ok(u) => u,
+                    Err(_) => whole_unsigned(s.parse().ok()?)?,
+                })
+            }
+            (DstType::Unsigned, Value::Bool(b)) => DBValue::UnsignedInteger(u64::from(*b)),
+            (DstType::Boolean, Value::Bool(b)) => DBValue::Boolean(*b),
+            (DstType::Boolean, Value::Number(n)) => match n.as_i64()? {
+                0 => DBValue::Boolean(false),
+                1 => DBValue::Boolean(true),
+                _ => return None,
+            },
+            (DstType::Boolean, Value::String(s)) => match s.trim().to_lowercase().as_str() {
+                "true" | "on" | "yes" | "1" => DBValue::Boolean(true),
+                "false" | "off" | "no" | "0" => DBValue::Boolean(false),
+                _ => return None,
+            },
+            (DstType::String, Value::String(s)) => DBValue::String(s.to_owned().into()),
+            (DstType::String, Value::Null) => return None,
+            (DstType::String, value) => DBValue::String(value.to_string().into()),
+            _ => return None,
+        })
+    }
+}
+
+/// Convert a float to an integer if it has no fractional part.
+///
+/// `i64::MAX as f64` is rounded up to 2^63, which no longer fits.
+fn whole(x: f64) -> Option<i64> {
+    (x.fract() == 0.0 && x >= i64::MIN as f64 && x < i64::MAX as f64).then_some(x as i64)
+}
+
+/// Convert a float to an unsigned integer if it has no fractional part and is not negative.
+fn whole_unsigned(x: f64) -> Option<u64> {
+    (x.fract() == 0.0 && x >= 0.0 && x < u64::MAX as f64).then_some(x as u64)
+}
+
+/// Linear transform `value * scale + offset` for numeric values.
+#[derive(Debug, Default, Deserialize)]
+pub struct Transform {
+    scale: Option<f64>,
+    offset: Option<f64>,
+}
+
+impl Transform {
+    pub fn is_identity(&self) -> bool {
+        self.scale.is_none() && self.offset.is_none()
+    }
+
+    pub fn apply(&self, value: f64) -> f64 {
+        value * self.scale.unwrap_or(1.0) + self.offset.unwrap_or(0.0)
+    }
+}
+
+/// What to do when a JSON field resolves to `null`.
+#[derive(Debug, Default, Deserialize)]
+pub enum NullPolicy {
+    /// Leave the field out of the point.
+    #[default]
+    Skip,
+    /// Write a zero of the `dst_type` of the field instead, or of the type of the values written
+    /// for it before, as InfluxDB rejects values of another type. That is `0.0` for transformed
+    /// values, and when the type is not known yet.
+    Zero,
+    /// Reject the whole message.
+    Error,
+}
+
+/// What to do with a float field that is NaN or infinite, which InfluxDB rejects along with the
+/// rest of the batch.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+pub enum NonFinitePolicy {
+    /// Leave the field out of the point.
+    #[default]
+    Skip,
+    /// Write `0.0` instead.
+    Zero,
+    /// Reject the whole message.
+    Error,
+}
+
+impl NonFinitePolicy {
+    /// Apply the policy to the float fields of `point` that are NaN or infinite.
+    fn apply<'a>(self, mut point: Point<'a>) -> Result<Point<'a>, ExtractError> {
+        let non_finite = |value: &DBValue| matches!(value, DBValue::Float(x) if !x.is_finite());
+        match self {
+            NonFinitePolicy::Skip => point.fields.retain(|name, value| {
+                if non_finite(value) {
+                    log::debug!("{} is {:?}, skipping it", name, value);
+                }
+                !non_finite(value)
+            }),
+            NonFinitePolicy::Zero => {
+                for value in point.fields.values_mut().filter(|value| non_finite(value)) {
+                    *value = DBValue::Float(0.0);
+                }
+            }
+            NonFinitePolicy::Error => {
+                if let Some((name, DBValue::Float(x))) =
+                    point.fields.iter().find(|(_, value)| non_finite(value))
+                {
+                    return Err(ExtractError::NonFinite {
+                        field: name.clone(),
+                        value: *x,
+                    });
+                }
+            }
+        }
+        Ok(point)
+    }
+}
+
+/// What to do with a value that is missing from its map.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+pub enum UnmappedPolicy {
+    /// Reject the whole message.
+    #[default]
+    Error,
+    /// Write the value as it is.
+    Keep,
+}
+
+/// Look up `key` in `map`, comparing regardless of case if `ignore_case` is set.
+fn lookup<'m, V>(map: &'m BTreeMap<String, V>, key: &str, ignore_case: bool) -> Option<&'m V> {
+    if ignore_case {
+        let key = key.to_lowercase();
+        map.iter()
+            .find_map(|(k, v)| (k.to_lowercase() == key).then_some(v))
+    } else {
+        map.get(key)
+    }
+}
+
+/// How the `src_path` of a JSON field is interpreted.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PathSyntax {
+    /// Keys and array indices separated by dots, such as `lines.0.voltage`.
+    #[default]
+    Dotted,
+    /// A JSONPath expression such as `$['a.b']` or `$.items[?(@.id == 'x')].v`. If the
+    /// expression matches several values, the first one is used.
+    JsonPath,
+}
+
+/// How to write a JSON field whose value is an array, such as `[1, 2, 3]`, instead of storing
+/// it as JSON text.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArrayMode {
+    /// The element at this index. The field is left out if the array is shorter.
+    Index(usize),
+    /// The first element. The field is left out if the array is empty.
+    First,
+    /// The last element. The field is left out if the array is empty.
+    Last,
+    /// The sum of the elements, which must all be numbers. An integer if they all are.
+    Sum,
+    /// The mean of the elements as a float, which must all be numbers. The field is left out if
+    /// the array is empty.
+    Mean,
+    /// The elements as a string, separated by this text, such as `","`.
+    Join(String),
+}
+
+impl ArrayMode {
+    /// The value to write for the array `elements` of `field`, or `None` to leave it out.
+    fn apply(&self, field: &str, elements: &[Value]) -> Result<Option<Value>, ExtractError> {
+        let numbers = || {
+            elements
+                .iter()
+                .map(|element| {
+                    element
+                        .as_f64()
+                        .ok_or_else(|| ExtractError::NotNumericArray {
+                            field: field.to_owned(),
+                            value: element.to_string(),
+                        })
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        Ok(match self {
+            ArrayMode::Index(i) => elements.get(*i).cloned(),
+            ArrayMode::First => elements.first().cloned(),
+            ArrayMode::Last => elements.last().cloned(),
+            ArrayMode::Sum => {
+                let integers: Option<Vec<i64>> = elements.iter().map(Value::as_i64).collect();
+                match integers.and_then(|i| i.into_iter().try_fold(0i64, i64::checked_add)) {
+                    Some(sum) => Some(Value::from(sum)),
+                    None => Some(Value::from(numbers()?.into_iter().sum::<f64>())),
+                }
+            }
+            ArrayMode::Mean => {
+                let numbers = numbers()?;
+                (!numbers.is_empty())
+                    .then(|| Value::from(numbers.iter().sum::<f64>() / numbers.len() as f64))
+            }
+            ArrayMode::Join(separator) => {
+                let elements: Vec<_> = elements
+                    .iter()
+                    .map(|element| match element {
+                        Value::String(s) => s.clone(),
+                        element => element.to_string(),
+                    })
+                    .collect();
+                Some(Value::String(elements.join(separator)))
+            }
+        })
+    }
+}
+
+/// Where the value of a JSON field is in the payload, with JSONPath expressions parsed when
+/// the configuration is loaded.
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "SrcPathConfig")]
+pub struct SrcPath {
+    path: String,
+    jsonpath: Option<JsonPath>,
+}
+
+#[derive(Deserialize)]
+struct SrcPathConfig {
+    src_path: String,
+    #[serde(default)]
+    path_syntax: PathSyntax,
+}
+
+impl TryFrom<SrcPathConfig> for SrcPath {
+    type Error = String;
+
+    fn try_from(config: SrcPathConfig) -> Result<SrcPath, String> {
+        let jsonpath = match config.path_syntax {
+            PathSyntax::Dotted => None,
+            PathSyntax::JsonPath => Some(
+                JsonPath::parse(&config.src_path)
+                    .map_err(|e| format!("src_path {:?}: {}", config.src_path, e))?,
+            ),
+        };
+        Ok(SrcPath {
+            path: config.src_path,
+            jsonpath,
+        })
+    }
+}
+
+impl SrcPath {
+    pub fn as_str(&self) -> &str {
+        &self.path
+    }
+
+    /// Resolve the path against a payload, or `None` if any part of it is missing.
+    pub fn resolve<'v>(&self, value: &'v Value) -> Option<&'v Value> {
+        match &self.jsonpath {
+            None => resolve_path(value, &self.path),
+            Some(jsonpath) => jsonpath.query(value).first().copied(),
+        }
+    }
+}
+
+impl fmt::Display for SrcPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.path)
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct JsonField {
-    src_path: String,
+    #[serde(flatten)]
+    src_path: SrcPath,
     #[serde(default = "DstVariant::default")]
     dst_variant: DstVariant,
+    /// Name of the field or tag, `src_path` by default. Placeholders such as `{sensor}` are
+    /// filled in with the value at that dotted path in the payload, so the name can be taken
+    /// from the payload itself.
     dst_name: Option<String>,
+    #[serde(default)]
+    on_null: NullPolicy,
+    /// Convert the value to this type instead of inferring it from the JSON value.
+    dst_type: Option<DstType>,
+    /// Transform applied to numeric values. The value is resolved first, then transformed, and
+    /// only then converted to `dst_type`. Configuring a transform on a non-numeric value is an
+    /// error, `null` values are left to `on_null`.
+    #[serde(flatten)]
+    transform: Transform,
+    /// Translate string values before they are transformed and converted, such as
+    /// `{low: 0, high: 1}`.
+    value_map: Option<BTreeMap<String, Value>>,
+    /// What to do with string values missing from `value_map`.
+    #[serde(default)]
+    on_unmapped: UnmappedPolicy,
+    /// How to turn an array value into a single value, before it is mapped, transformed and
+    /// converted. Values that are not arrays are used as they are.
+    array: Option<ArrayMode>,
+    /// Type of the last value written for the field, for `on_null: Zero`.
+    #[serde(skip)]
+    last_type: Mutex<Option<DstType>>,
 }
 
 impl JsonField {
-    pub fn src_path_parts(&self) -> impl Iterator<Item = &str> {
-        self.src_path.split('.')
+    /// The value to write for `null` with `on_null: Zero`.
+    fn zero(&self) -> DBValue<'static> {
+        match self.dst_type.or(*self.last_type.lock().unwrap()) {
+            Some(dst_type) => dst_type.coerce(&Value::from(0)).unwrap(),
+            None => DBValue::Float(0.0),
+        }
+    }
+
+    /// Resolve `src_path` against a payload, or `None` if any part of it is missing.
+    pub fn resolve<'v>(&self, value: &'v Value) -> Option<&'v Value> {
+        self.src_path.resolve(value)
+    }
+
+    /// The name of the field or tag for a payload, or `None` if a placeholder in `dst_name` does
+    /// not resolve to a string, number or boolean.
+    fn dst_name<'f>(&'f self, value: &Value) -> Option<Cow<'f, str>> {
+        let template = self.dst_name.as_deref().unwrap_or(self.src_path.as_str());
+        if !template.contains('{') {
+            return Some(Cow::Borrowed(template));
+        }
+
+        let mut name = String::with_capacity(template.len());
+        let mut rest = template;
+        // Placeholders were checked when the configuration was loaded.
+        while let Some((literal, placeholder)) = rest.split_once('{') {
+            let (path, after) = placeholder.split_once('}').unwrap();
+            name.push_str(literal);
+            match resolve_path(value, path)? {
+                Value::String(s) => name.push_str(s),
+                value @ (Value::Number(_) | Value::Bool(_)) => name.push_str(&value.to_string()),
+                _ => return None,
+            }
+            rest = after;
+        }
+        name.push_str(rest);
+        (!name.is_empty()).then_some(Cow::Owned(name))
+    }
+}
+
+/// Wire format of payloads of a `json` entry.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JsonFormat {
+    #[default]
+    Json,
+    /// MessagePack, which is decoded to JSON before applying the fields.
+    MsgPack,
+}
+
+impl JsonFormat {
+    fn decode(&self, value: &[u8]) -> Result<Value, ExtractError> {
+        match self {
+            JsonFormat::Json => {
+                serde_json::from_slice(value).map_err(|source| ExtractError::InvalidJson {
+                    source,
+                    preview: preview(value),
+                })
+            }
+            JsonFormat::MsgPack => {
+                msgpack::decode(value).map_err(|reason| ExtractError::InvalidMsgPack {
+                    reason,
+                    preview: preview(value),
+                })
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CsvColumn {
+    /// Position of the column in the row, starting at 0.
+    index: usize,
+    dst_name: String,
+    #[serde(default = "DstVariant::default")]
+    dst_variant: DstVariant,
+    /// Convert the value to this type instead of inferring it from the text.
+    dst_type: Option<DstType>,
+}
+
+/// Wire format of payloads of a `binary` entry.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BinaryFormat {
+    /// The bytes as they are.
+    #[default]
+    Raw,
+    /// Text of hexadecimal digits, such as `01A3F0`.
+    Hex,
+}
+
+/// How a number is stored in a binary payload.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BinaryType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+}
+
+impl fmt::Display for BinaryType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BinaryType::U8 => "u8",
+            BinaryType::I8 => "i8",
+            BinaryType::U16 => "u16",
+            BinaryType::I16 => "i16",
+            BinaryType::U32 => "u32",
+            BinaryType::I32 => "i32",
+            BinaryType::F32 => "f32",
+        })
+    }
+}
+
+impl BinaryType {
+    /// Number of bytes taken up by the number.
+    fn len(self) -> usize {
+        match self {
+            BinaryType::U8 | BinaryType::I8 => 1,
+            BinaryType::U16 | BinaryType::I16 => 2,
+            BinaryType::U32 | BinaryType::I32 | BinaryType::F32 => 4,
+        }
+    }
+
+    /// Read the number from the first `len` bytes, most significant byte first.
+    fn read(self, bytes: [u8; 4]) -> f64 {
+        let [a, b, ..] = bytes;
+        match self {
+            BinaryType::U8 => f64::from(a),
+            BinaryType::I8 => f64::from(a as i8),
+            BinaryType::U16 => f64::from(u16::from_be_bytes([a, b])),
+            BinaryType::I16 => f64::from(i16::from_be_bytes([a, b])),
+            BinaryType::U32 => f64::from(u32::from_be_bytes(bytes)),
+            BinaryType::I32 => f64::from(i32::from_be_bytes(bytes)),
+            BinaryType::F32 => f64::from(f32::from_be_bytes(bytes)),
+        }
+    }
+}
+
+/// Byte order of numbers in a binary payload.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Endianness {
+    #[default]
+    Big,
+    Little,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BinaryField {
+    /// Position of the first byte of the number in the payload, starting at 0.
+    index: usize,
+    #[serde(rename = "type")]
+    binary_type: BinaryType,
+    #[serde(default)]
+    endianness: Endianness,
+    dst_name: String,
+    #[serde(default = "DstVariant::default")]
+    dst_variant: DstVariant,
+    /// Transform applied to the number, which is then stored as a float. Without one, integers
+    /// are stored as integers.
+    #[serde(flatten)]
+    transform: Transform,
+}
+
+impl BinaryField {
+    fn decode(&self, bytes: &[u8]) -> Result<DBValue<'static>, ExtractError> {
+        let len = self.binary_type.len();
+        let slice = self
+            .index
+            .checked_add(len)
+            .and_then(|end| bytes.get(self.index..end))
+            .ok_or_else(|| ExtractError::TooShort {
+                field: self.dst_name.clone(),
+                len: bytes.len(),
+            })?;
+
+        let mut be = [0; 4];
+        be[..len].copy_from_slice(slice);
+        if let Endianness::Little = self.endianness {
+            be[..len].reverse();
+        }
+        let x = self.binary_type.read(be);
+
+        Ok(match self.binary_type {
+            BinaryType::F32 => DBValue::Float(self.transform.apply(x)),
+            _ if !self.transform.is_identity() => DBValue::Float(self.transform.apply(x)),
+            _ => DBValue::Integer(x as i64),
+        })
     }
 }
 
@@ -43,81 +823,2309 @@ pub enum Fields {
     SingleText {
         #[serde(default = "DstVariant::default")]
         dst_variant: DstVariant,
+        /// Name of the field or tag to store the payload in. This cannot be called `dst_name`,
+        /// as that key already holds the measurement name of the entry.
+        #[serde(rename = "value_name", default = "default_value_name")]
         dst_name: String,
+        /// Convert the payload to this type instead of storing it as a string.
+        dst_type: Option<DstType>,
+        /// Store payloads such as `23.5`, `42` or `true` as a float, integer or boolean, and all
+        /// other payloads as a string.
+        #[serde(default)]
+        infer_type: bool,
+        /// Parse the payload as a number and transform it, storing it as a float by default.
+        #[serde(flatten)]
+        transform: Transform,
+        /// Store the payload as a boolean according to this map, such as `{ON: true, OFF: false}`.
+        /// Whitespace around the payload is ignored.
+        bool_map: Option<BTreeMap<String, bool>>,
+        /// Match the keys of `bool_map` regardless of case.
+        #[serde(default)]
+        ignore_case: bool,
+        /// What to do with payloads missing from `bool_map`; `Keep` stores them as a string.
+        #[serde(default)]
+        on_unmapped: UnmappedPolicy,
     },
     Json {
+        #[serde(default)]
+        format: JsonFormat,
         fields: Vec<JsonField>,
     },
+    /// One point per element of a JSON array, with `fields` resolved relative to the element,
+    /// such as `{"readings": [{"ts": 1, "v": 10}, {"ts": 2, "v": 20}]}` with `src_path:
+    /// readings`. The `timestamp` is resolved relative to the element as well, while `filter`
+    /// applies to the whole payload. An empty array produces no points.
+    JsonArray {
+        #[serde(default)]
+        format: JsonFormat,
+        /// Dotted path to the array, or the payload itself if it is an array when left out.
+        src_path: Option<String>,
+        fields: Vec<JsonField>,
+    },
+    /// Every top-level key of a JSON object as a field of the same name, with its type inferred
+    /// from the value. `null` values are left out.
+    JsonAll {
+        #[serde(default)]
+        format: JsonFormat,
+        /// Keys to leave out of the point.
+        #[serde(default)]
+        exclude: Vec<String>,
+        /// Patterns of keys to write as tags instead of fields, such as `meta_*`. A `*` matches
+        /// any number of characters.
+        #[serde(default)]
+        tag_keys: Vec<String>,
+    },
+    /// Every leaf of a JSON object as a field named by its dotted path, such as `env.temp` for
+    /// `{"env": {"temp": 21}}`, with its type inferred from the value. Arrays are stored as JSON
+    /// text, and `null` values are left out.
+    JsonFlatten {
+        #[serde(default)]
+        format: JsonFormat,
+        /// Number of levels of objects to walk, storing objects below as JSON text. All levels are
+        /// walked when left out.
+        max_depth: Option<usize>,
+        /// Dotted paths to leave out of the point, along with everything below them.
+        #[serde(default)]
+        exclude: Vec<String>,
+    },
+    /// A single row of delimiter separated values, such as `23.5,60,1013`. Columns missing from
+    /// the row, or empty, are left out of the point.
+    Csv {
+        #[serde(default = "default_delimiter")]
+        delimiter: char,
+        columns: Vec<CsvColumn>,
+    },
+    /// Numbers at fixed positions in a binary payload, as sent by many LoRaWAN devices.
+    Binary {
+        #[serde(default)]
+        format: BinaryFormat,
+        fields: Vec<BinaryField>,
+    },
+}
+
+fn default_value_name() -> String {
+    "value".to_owned()
+}
+
+fn default_delimiter() -> char {
+    ','
+}
+
+/// The names of the `{name}` placeholders in `template`.
+fn placeholders(template: &str) -> Result<Vec<&str>, String> {
+    let mut names = Vec::new();
+    let mut rest = template;
+    while let Some((literal, placeholder)) = rest.split_once('{') {
+        if literal.contains('}') {
+            return Err("unmatched \"}\"".to_owned());
+        }
+        let Some((name, after)) = placeholder.split_once('}') else {
+            return Err("unclosed \"{\"".to_owned());
+        };
+        if name.is_empty() || name.contains('{') {
+            return Err(format!("invalid placeholder {{{}}}", name));
+        }
+        names.push(name);
+        rest = after;
+    }
+    if rest.contains('}') {
+        return Err("unmatched \"}\"".to_owned());
+    }
+    Ok(names)
+}
+
+/// Whether `s` matches `pattern`, in which `*` matches any number of characters.
+fn glob_matches(pattern: &str, s: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = s.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts: Vec<_> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // No `*` in the pattern.
+        return rest.is_empty();
+    };
+
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Add the leaves of the object `value` to `point` as fields named by their dotted path after
+/// `prefix`, walking at most `depth` levels of objects.
+fn flatten<'a>(
+    value: &Value,
+    prefix: &str,
+    depth: Option<usize>,
+    exclude: &[String],
+    mut point: Point<'a>,
+) -> Point<'a> {
+    for (key, value) in value.as_object().into_iter().flatten() {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        if exclude.contains(&path) {
+            continue;
+        }
+        match value {
+            Value::Object(_) if depth != Some(1) => {
+                point = flatten(value, &path, depth.map(|d| d - 1), exclude, point);
+            }
+            value => {
+                if let Some(value) = json_to_influxdb(value) {
+                    point = point.add_field(path, value);
+                }
+            }
+        }
+    }
+    point
+}
+
+/// Convert text to an integer or float if it is one, and to a string otherwise.
+fn infer_text(s: &str) -> DBValue<'static> {
+    if let Ok(i) = s.parse() {
+        DBValue::Integer(i)
+    } else if let Ok(u) = s.parse() {
+        DBValue::UnsignedInteger(u)
+    } else if let Ok(x) = s.parse() {
+        DBValue::Float(x)
+    } else {
+        DBValue::String(s.to_owned().into())
+    }
 }
 
-fn json_to_influxdb(value: &Value) -> DBValue<'static> {
-    match value {
-        Value::Null => unimplemented!(),
+/// Convert a JSON value to its InfluxDB counterpart, or `None` for `null`.
+///
+/// Whole numbers become integers, or unsigned integers beyond the range of `i64`, all other
+/// numbers become floats.
+fn json_to_influxdb(value: &Value) -> Option<DBValue<'static>> {
+    Some(match value {
+        Value::Null => return None,
         Value::Bool(b) => DBValue::Boolean(*b),
-        Value::Number(n) => DBValue::Float(n.as_f64().unwrap()),
+        Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) => DBValue::Integer(i),
+            (None, Some(u)) => DBValue::UnsignedInteger(u),
+            (None, None) => DBValue::Float(n.as_f64().unwrap()),
+        },
         Value::String(s) => DBValue::String(s.to_owned().into()),
         Value::Array(a) => DBValue::String(serde_json::to_string(&a).unwrap().into()),
         Value::Object(o) => DBValue::String(serde_json::to_string(&o).unwrap().into()),
-    }
+    })
 }
 
-impl Fields {
-    pub fn extract<'a>(&self, value: &[u8], mut point: Point<'a>) -> Point<'a> {
+impl fmt::Display for Fields {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Fields::SingleText {
                 dst_variant,
                 dst_name,
+                ..
+            } => write!(f, "text -> {} {}", dst_variant, dst_name),
+            Fields::Json { format, fields } => {
+                f.write_str(match format {
+                    JsonFormat::Json => "json {",
+                    JsonFormat::MsgPack => "msgpack {",
+                })?;
+                for (i, field) in fields.iter().enumerate() {
+                    let dst_name = field.dst_name.as_deref().unwrap_or(field.src_path.as_str());
+                    let separator = if i == 0 { " " } else { ", " };
+                    write!(
+                        f,
+                        "{}{} -> {} {}",
+                        separator, field.src_path, field.dst_variant, dst_name
+                    )?;
+                }
+                f.write_str(" }")
+            }
+            Fields::JsonArray {
+                format,
+                src_path,
+                fields,
             } => {
-                let value = std::str::from_utf8(value).unwrap().to_owned();
-                let value = DBValue::String(value.into());
-
-                point = dst_variant.write_to(dst_name, value, point);
+                f.write_str(match format {
+                    JsonFormat::Json => "json",
+                    JsonFormat::MsgPack => "msgpack",
+                })?;
+                write!(f, " array {} {{", src_path.as_deref().unwrap_or("$"))?;
+                for (i, field) in fields.iter().enumerate() {
+                    let dst_name = field.dst_name.as_deref().unwrap_or(field.src_path.as_str());
+                    let separator = if i == 0 { " " } else { ", " };
+                    write!(
+                        f,
+                        "{}{} -> {} {}",
+                        separator, field.src_path, field.dst_variant, dst_name
+                    )?;
+                }
+                f.write_str(" }")
             }
-            Fields::Json { fields } => {
-                let value: Value = serde_json::from_slice(value).unwrap();
-
-                for field in fields {
-                    let dst_name = field.dst_name.as_ref().unwrap_or(&field.src_path);
-
-                    let mut value = &value;
-                    for p in field.src_path_parts() {
-                        if let Some(v) = match value {
-                            Value::Array(a) => {
-                                if let Ok(i) = p.parse::<usize>() {
-                                    a.get(i)
-                                } else {
-                                    continue;
-                                }
-                            }
-                            Value::Object(o) => o.get(p),
-                            _ => continue,
-                        } {
-                            value = v;
-                        } else {
-                            continue;
-                        }
-                    }
-
-                    let value = json_to_influxdb(value);
-                    point = field.dst_variant.write_to(dst_name, value, point);
+            Fields::JsonAll {
+                format,
+                exclude,
+                tag_keys,
+            } => {
+                f.write_str(match format {
+                    JsonFormat::Json => "json",
+                    JsonFormat::MsgPack => "msgpack",
+                })?;
+                f.write_str(" {")?;
+                for pattern in tag_keys {
+                    write!(f, " {} -> tag {},", pattern, pattern)?;
+                }
+                f.write_str(" * -> field *")?;
+                for key in exclude {
+                    write!(f, ", except {}", key)?;
+                }
+                f.write_str(" }")
+            }
+            Fields::JsonFlatten {
+                format,
+                max_depth,
+                exclude,
+            } => {
+                f.write_str(match format {
+                    JsonFormat::Json => "json",
+                    JsonFormat::MsgPack => "msgpack",
+                })?;
+                f.write_str(" { **")?;
+                if let Some(max_depth) = max_depth {
+                    write!(f, " ({} levels)", max_depth)?;
+                }
+                f.write_str(" -> field **")?;
+                for path in exclude {
+                    write!(f, ", except {}", path)?;
+                }
+                f.write_str(" }")
+            }
+            Fields::Csv { columns, .. } => {
+                f.write_str("csv {")?;
+                for (i, column) in columns.iter().enumerate() {
+                    let separator = if i == 0 { " " } else { ", " };
+                    write!(
+                        f,
+                        "{}{} -> {} {}",
+                        separator, column.index, column.dst_variant, column.dst_name
+                    )?;
                 }
+                f.write_str(" }")
+            }
+            Fields::Binary { format, fields } => {
+                f.write_str(match format {
+                    BinaryFormat::Raw => "binary {",
+                    BinaryFormat::Hex => "hex {",
+                })?;
+                for (i, field) in fields.iter().enumerate() {
+                    let separator = if i == 0 { " " } else { ", " };
+                    write!(
+                        f,
+                        "{}{} {} -> {} {}",
+                        separator,
+                        field.index,
+                        field.binary_type,
+                        field.dst_variant,
+                        field.dst_name
+                    )?;
+                }
+                f.write_str(" }")
             }
         }
-
-        point
     }
 }
 
-#[derive(Debug, Deserialize)]
-pub struct Entry {
-    pub src_topic: String,
-    pub dst_name: String,
-    pub throttle_ms: Option<u64>,
-    #[serde(flatten)]
-    pub fields: Fields,
+/// Resolve a dotted path such as `lines.0.voltage` against a JSON value.
+fn resolve_path<'v>(value: &'v Value, path: &str) -> Option<&'v Value> {
+    path.split('.').try_fold(value, |value, p| match value {
+        Value::Array(a) => a.get(p.parse::<usize>().ok()?),
+        Value::Object(o) => o.get(p),
+        _ => None,
+    })
 }
 
-#[derive(Debug, Deserialize)]
-pub struct Configuration {
-    pub entries: Vec<Entry>,
+/// A received payload, decoded according to the `Fields` variant of its entry.
+///
+/// CSV payloads are decoded to a JSON array of strings, so their columns can be addressed by
+/// index like JSON arrays.
+#[derive(Clone)]
+pub enum Payload {
+    Text(String),
+    Json(Value),
+    Bytes(Vec<u8>),
+}
+
+fn decode_text(value: &[u8]) -> Result<&str, ExtractError> {
+    std::str::from_utf8(value).map_err(|_| ExtractError::InvalidUtf8 { len: value.len() })
+}
+
+impl Fields {
+    /// Whether the points get tags from the payload.
+    fn has_tags(&self) -> bool {
+        let is_tag = |variant: &DstVariant| matches!(variant, DstVariant::Tag);
+        match self {
+            Fields::SingleText { dst_variant, .. } => is_tag(dst_variant),
+            Fields::Json { fields, .. } | Fields::JsonArray { fields, .. } => {
+                fields.iter().any(|field| is_tag(&field.dst_variant))
+            }
+            Fields::JsonAll { tag_keys, .. } => !tag_keys.is_empty(),
+            Fields::JsonFlatten { .. } => false,
+            Fields::Csv { columns, .. } => columns.iter().any(|column| is_tag(&column.dst_variant)),
+            Fields::Binary { fields, .. } => fields.iter().any(|field| is_tag(&field.dst_variant)),
+        }
+    }
+
+    /// Whether the points may get fields from the payload, as InfluxDB rejects points without.
+    fn has_fields(&self) -> bool {
+        let is_field = |variant: &DstVariant| matches!(variant, DstVariant::Field);
+        match self {
+            Fields::SingleText { dst_variant, .. } => is_field(dst_variant),
+            Fields::Json { fields, .. } | Fields::JsonArray { fields, .. } => {
+                fields.iter().any(|field| is_field(&field.dst_variant))
+            }
+            Fields::JsonAll { .. } | Fields::JsonFlatten { .. } => true,
+            Fields::Csv { columns, .. } => {
+                columns.iter().any(|column| is_field(&column.dst_variant))
+            }
+            Fields::Binary { fields, .. } => {
+                fields.iter().any(|field| is_field(&field.dst_variant))
+            }
+        }
+    }
+
+    pub fn decode(&self, value: &[u8]) -> Result<Payload, ExtractError> {
+        Ok(match self {
+            Fields::SingleText { .. } => Payload::Text(decode_text(value)?.to_owned()),
+            Fields::Csv { delimiter, .. } => Payload::Json(Value::Array(
+                decode_text(value)?
+                    .trim()
+                    .split(*delimiter)
+                    .map(|cell| Value::String(cell.trim().to_owned()))
+                    .collect(),
+            )),
+            Fields::Json { format, .. }
+            | Fields::JsonArray { format, .. }
+            | Fields::JsonAll { format, .. }
+            | Fields::JsonFlatten { format, .. } => Payload::Json(format.decode(value)?),
+            Fields::Binary {
+                format: BinaryFormat::Raw,
+                ..
+            } => Payload::Bytes(value.to_vec()),
+            Fields::Binary {
+                format: BinaryFormat::Hex,
+                ..
+            } => Payload::Bytes(
+                hex::decode(value).map_err(|reason| ExtractError::InvalidHex {
+                    reason,
+                    preview: preview(value),
+                })?,
+            ),
+        })
+    }
+
+    /// The parts of a decoded payload that each become a point: the elements of the array for
+    /// `json_array`, and the whole payload otherwise.
+    pub fn records<'p>(&self, payload: &'p Payload) -> Result<Vec<Cow<'p, Payload>>, ExtractError> {
+        match (self, payload) {
+            (Fields::JsonArray { src_path, .. }, Payload::Json(value)) => {
+                let array = match src_path {
+                    Some(src_path) => resolve_path(value, src_path),
+                    None => Some(value),
+                };
+                match array {
+                    Some(Value::Array(elements)) => Ok(elements
+                        .iter()
+                        .map(|element| Cow::Owned(Payload::Json(element.clone())))
+                        .collect()),
+                    _ => Err(ExtractError::NotArray {
+                        src_path: src_path.clone(),
+                    }),
+                }
+            }
+            _ => Ok(vec![Cow::Borrowed(payload)]),
+        }
+    }
+
+    pub fn extract<'a>(
+        &self,
+        payload: &Payload,
+        mut point: Point<'a>,
+    ) -> Result<Point<'a>, ExtractError> {
+        match (self, payload) {
+            (
+                Fields::SingleText {
+                    dst_variant,
+                    dst_name,
+                    dst_type,
+                    infer_type,
+                    transform,
+                    bool_map,
+                    ignore_case,
+                    on_unmapped,
+                },
+                Payload::Text(text),
+            ) => {
+                let value = if let Some(bool_map) = bool_map {
+                    match lookup(bool_map, text.trim(), *ignore_case) {
+                        Some(b) => DBValue::Boolean(*b),
+                        None => match on_unmapped {
+                            UnmappedPolicy::Error => {
+                                return Err(ExtractError::Unmapped {
+                                    field: dst_name.clone(),
+                                    value: Value::String(text.clone()).to_string(),
+                                })
+                            }
+                            UnmappedPolicy::Keep => DBValue::String(text.clone().into()),
+                        },
+                    }
+                } else {
+                    let (value, dst_type) = if transform.is_identity() {
+                        (Value::String(text.clone()), *dst_type)
+                    } else {
+                        let x =
+                            text.trim()
+                                .parse::<f64>()
+                                .map_err(|_| ExtractError::NotNumeric {
+                                    field: dst_name.clone(),
+                                    value: Value::String(text.clone()).to_string(),
+                                })?;
+                        (
+                            Value::from(transform.apply(x)),
+                            dst_type.or(Some(DstType::Float)),
+                        )
+                    };
+
+                    match dst_type {
+                        Some(dst_type) => {
+                            dst_type
+                                .coerce(&value)
+                                .ok_or_else(|| ExtractError::Coerce {
+                                    field: dst_name.clone(),
+                                    dst_type,
+                                    value: value.to_string(),
+                                })?
+                        }
+                        None if *infer_type => match text.trim() {
+                            "true" => DBValue::Boolean(true),
+                            "false" => DBValue::Boolean(false),
+                            trimmed => match infer_text(trimmed) {
+                                DBValue::String(_) => DBValue::String(text.clone().into()),
+                                value => value,
+                            },
+                        },
+                        None => DBValue::String(text.clone().into()),
+                    }
+                };
+
+                point = dst_variant.write_to(dst_name, value, point);
+            }
+            (
+                Fields::Json { fields, .. } | Fields::JsonArray { fields, .. },
+                Payload::Json(value),
+            ) => {
+                for field in fields {
+                    let Some(dst_name) = field.dst_name(value) else {
+                        log::debug!(
+                            "Name of {} not found in payload, skipping it",
+                            field.src_path
+                        );
+                        continue;
+                    };
+
+                    // Fields that are missing from the payload are left out of the point.
+                    let Some(value) = field.resolve(value) else {
+                        log::debug!("{} not found in payload, skipping it", field.src_path);
+                        continue;
+                    };
+
+                    let reduced;
+                    let value = match (&field.array, value) {
+                        (Some(mode), Value::Array(elements)) => {
+                            match mode.apply(field.src_path.as_str(), elements)? {
+                                Some(value) => {
+                                    reduced = value;
+                                    &reduced
+                                }
+                                None => {
+                                    log::debug!(
+                                        "{} has no such element, skipping it",
+                                        field.src_path
+                                    );
+                                    continue;
+                                }
+                            }
+                        }
+                        _ => value,
+                    };
+
+                    let value = match (&field.value_map, value) {
+                        (Some(value_map), Value::String(s)) => match value_map.get(s) {
+                            Some(mapped) => mapped,
+                            None => match field.on_unmapped {
+                                UnmappedPolicy::Error => {
+                                    return Err(ExtractError::Unmapped {
+                                        field: field.src_path.to_string(),
+                                        value: value.to_string(),
+                                    })
+                                }
+                                UnmappedPolicy::Keep => value,
+                            },
+                        },
+                        _ => value,
+                    };
+
+                    let transformed;
+                    let value = match value {
+                        Value::Null => value,
+                        _ if field.transform.is_identity() => value,
+                        Value::Number(n) => {
+                            transformed = Value::from(field.transform.apply(n.as_f64().unwrap()));
+                            &transformed
+                        }
+                        _ => {
+                            return Err(ExtractError::NotNumeric {
+                                field: field.src_path.to_string(),
+                                value: value.to_string(),
+                            })
+                        }
+                    };
+
+                    let converted = match (value, field.dst_type) {
+                        (Value::Null, _) => None,
+                        (value, Some(dst_type)) => {
+                            Some(dst_type.coerce(value).ok_or_else(|| ExtractError::Coerce {
+                                field: field.src_path.to_string(),
+                                dst_type,
+                                value: value.to_string(),
+                            })?)
+                        }
+                        (value, None) => json_to_influxdb(value),
+                    };
+
+                    let value = match converted {
+                        Some(value) => {
+                            *field.last_type.lock().unwrap() = Some(DstType::of(&value));
+                            value
+                        }
+                        None => match field.on_null {
+                            NullPolicy::Skip => continue,
+                            NullPolicy::Zero => field.zero(),
+                            NullPolicy::Error => {
+                                return Err(ExtractError::NullValue {
+                                    src_path: field.src_path.to_string(),
+                                })
+                            }
+                        },
+                    };
+                    point = field.dst_variant.write_to(&dst_name, value, point);
+                }
+            }
+            (
+                Fields::JsonAll {
+                    exclude, tag_keys, ..
+                },
+                Payload::Json(value),
+            ) => {
+                for (key, value) in value.as_object().into_iter().flatten() {
+                    if exclude.contains(key) {
+                        continue;
+                    }
+                    let dst_variant = if tag_keys.iter().any(|p| glob_matches(p, key)) {
+                        DstVariant::Tag
+                    } else {
+                        DstVariant::Field
+                    };
+                    if let Some(value) = json_to_influxdb(value) {
+                        point = dst_variant.write_to(key, value, point);
+                    }
+                }
+            }
+            (
+                Fields::JsonFlatten {
+                    max_depth, exclude, ..
+                },
+                Payload::Json(value),
+            ) => point = flatten(value, "", *max_depth, exclude, point),
+            (Fields::Csv { columns, .. }, Payload::Json(Value::Array(cells))) => {
+                for column in columns {
+                    let cell = match cells.get(column.index).and_then(Value::as_str) {
+                        Some(cell) if !cell.is_empty() => cell,
+                        _ => continue,
+                    };
+
+                    let value = match column.dst_type {
+                        Some(dst_type) => dst_type
+                            .coerce(&Value::String(cell.to_owned()))
+                            .ok_or_else(|| ExtractError::Coerce {
+                                field: column.dst_name.clone(),
+                                dst_type,
+                                value: Value::String(cell.to_owned()).to_string(),
+                            })?,
+                        None => infer_text(cell),
+                    };
+                    point = column.dst_variant.write_to(&column.dst_name, value, point);
+                }
+            }
+            (Fields::Binary { fields, .. }, Payload::Bytes(bytes)) => {
+                for field in fields {
+                    let value = field.decode(bytes)?;
+                    point = field.dst_variant.write_to(&field.dst_name, value, point);
+                }
+            }
+            _ => unreachable!("payload was not decoded by these fields"),
+        }
+
+        Ok(point)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampFormat {
+    /// Seconds since the Unix epoch, possibly fractional.
+    UnixSeconds,
+    /// Milliseconds since the Unix epoch.
+    UnixMillis,
+    /// An RFC 3339 date and time, e.g. `2022-09-03T21:14:17+02:00`.
+    Rfc3339,
+}
+
+/// Where to find the point timestamp in the message, and how to parse it.
+#[derive(Debug, Deserialize)]
+pub struct Timestamp {
+    /// Path to the timestamp for `json` entries, or its column index for `csv` entries. For
+    /// `single_text` the whole payload is used, unless `user_property` is set.
+    src_path: Option<String>,
+    /// Name of an MQTT v5 user property to take the timestamp from. Messages without it fall back
+    /// to `src_path` if set, and to the server time otherwise.
+    user_property: Option<String>,
+    format: TimestampFormat,
+}
+
+impl Timestamp {
+    /// Resolve the timestamp in nanoseconds since the Unix epoch from the `user_properties` of
+    /// the message or its `payload`, or `None` to use the server time.
+    pub fn resolve(
+        &self,
+        payload: &Payload,
+        user_properties: &[(String, String)],
+    ) -> Result<Option<i128>, ExtractError> {
+        let property = self
+            .user_property
+            .as_ref()
+            .and_then(|name| user_properties.iter().find(|(key, _)| key == name));
+        if let Some((_, value)) = property {
+            return self
+                .parse(&Value::String(value.trim().to_owned()))
+                .map(Some);
+        }
+        if self.user_property.is_some() && self.src_path.is_none() {
+            return Ok(None);
+        }
+
+        let path = self.src_path.as_deref().unwrap_or_default();
+        let value = match payload {
+            Payload::Text(s) => Value::String(s.trim().to_owned()),
+            Payload::Json(value) => resolve_path(value, path).cloned().ok_or_else(|| {
+                ExtractError::TimestampMissing {
+                    src_path: path.to_owned(),
+                }
+            })?,
+            // Rejected when the configuration was loaded.
+            Payload::Bytes(_) => {
+                return Err(ExtractError::TimestampMissing {
+                    src_path: path.to_owned(),
+                })
+            }
+        };
+        self.parse(&value).map(Some)
+    }
+
+    /// Parse a timestamp in the configured format into nanoseconds since the Unix epoch.
+    fn parse(&self, value: &Value) -> Result<i128, ExtractError> {
+        let invalid = || ExtractError::TimestampInvalid {
+            value: value.to_string(),
+        };
+
+        // Whole numbers are scaled exactly, as an `f64` cannot hold every nanosecond of current
+        // times. Only fractions go through `f64`, rounded to the nearest nanosecond.
+        let scaled = |unit: i128| {
+            let fraction = |x: f64| x.is_finite().then(|| (x * unit as f64).round() as i128);
+            match value {
+                Value::Number(n) => n
+                    .as_i64()
+                    .map(i128::from)
+                    .or_else(|| n.as_u64().map(i128::from))
+                    .map(|whole| whole.checked_mul(unit))
+                    .unwrap_or_else(|| n.as_f64().and_then(fraction)),
+                Value::String(s) => match s.parse::<i128>() {
+                    Ok(whole) => whole.checked_mul(unit),
+                    Err(_) => s.parse::<f64>().ok().and_then(fraction),
+                },
+                _ => None,
+            }
+        };
+
+        let nanos = match self.format {
+            TimestampFormat::UnixSeconds => scaled(1_000_000_000),
+            TimestampFormat::UnixMillis => scaled(1_000_000),
+            TimestampFormat::Rfc3339 => value
+                .as_str()
+                .and_then(|s| chrono::DateTime::parse_from_rfc3339(s).ok())
+                .and_then(|t| t.timestamp_nanos_opt())
+                .map(i128::from),
+        };
+
+        nanos.ok_or_else(invalid)
+    }
+}
+
+/// Express a timestamp in nanoseconds in the unit of `precision`, rounded to the nearest unit.
+fn timestamp_in(nanos: i128, precision: Precision) -> i64 {
+    let unit: i128 = match precision {
+        Precision::Nanoseconds => 1,
+        Precision::Microseconds => 1_000,
+        Precision::Milliseconds => 1_000_000,
+        Precision::Seconds => 1_000_000_000,
+        Precision::Minutes => 60_000_000_000,
+        Precision::Hours => 3_600_000_000_000,
+    };
+    (nanos + unit / 2).div_euclid(unit) as i64
+}
+
+/// A condition a JSON payload must meet for its message to be written.
+///
+/// Written as a path followed by an operator and a literal, such as `status == "active"` or
+/// `level > 0`. The operators are `==`, `!=`, `<`, `<=`, `>` and `>=`. The literal is a number,
+/// a string quoted with `"` or `'`, `true`, `false` or `null`. A path on its own, such as
+/// `battery`, only requires the value to be present and not `null`.
+///
+/// The path is dotted like `src_path`, or a JSONPath expression when it starts with `$`. Messages
+/// in which the path does not resolve never meet the condition.
+#[derive(Debug)]
+pub struct Condition {
+    src_path: String,
+    /// The parsed `src_path`, if it is a JSONPath expression.
+    jsonpath: Option<JsonPath>,
+    comparison: Option<(Op, Value)>,
+}
+
+impl Condition {
+    fn parse(s: &str) -> Result<Condition, String> {
+        let s = s.trim();
+
+        // The path ends at the operator, outside brackets and quotes of JSONPath expressions.
+        let mut depth = 0;
+        let mut quote = None;
+        let end = s
+            .char_indices()
+            .find(|&(_, c)| {
+                match (quote, c) {
+                    (Some(q), c) if c == q => quote = None,
+                    (Some(_), _) => {}
+                    (None, '\'' | '"') => quote = Some(c),
+                    (None, '[') => depth += 1,
+                    (None, ']') => depth -= 1,
+                    (None, c) => return depth == 0 && (c.is_whitespace() || "=!<>".contains(c)),
+                }
+                false
+            })
+            .map_or(s.len(), |(i, _)| i);
+
+        let (src_path, rest) = s.split_at(end);
+        if src_path.is_empty() {
+            return Err(format!("expected a path in {:?}", s));
+        }
+        let jsonpath = if src_path.starts_with('$') {
+            Some(JsonPath::parse(src_path)?)
+        } else {
+            None
+        };
+
+        let rest = rest.trim_start();
+        let comparison = if rest.is_empty() {
+            None
+        } else {
+            let (op, literal) = Op::strip_prefix(rest)
+                .ok_or_else(|| format!("expected ==, !=, <, <=, > or >= in {:?}", s))?;
+            let literal = literal.trim();
+            let literal = match literal
+                .strip_prefix('\'')
+                .and_then(|l| l.strip_suffix('\''))
+            {
+                Some(l) => Value::String(l.to_owned()),
+                None => serde_json::from_str(literal).map_err(|_| {
+                    format!(
+                        "expected a number, quoted string, true, false or null in {:?}",
+                        s
+                    )
+                })?,
+            };
+            Some((op, literal))
+        };
+
+        Ok(Condition {
+            src_path: src_path.to_owned(),
+            jsonpath,
+            comparison,
+        })
+    }
+
+    pub fn matches(&self, payload: &Value) -> bool {
+        let value = match &self.jsonpath {
+            Some(jsonpath) => jsonpath.query(payload).first().copied(),
+            None => resolve_path(payload, &self.src_path),
+        };
+
+        match (value, &self.comparison) {
+            (None, _) => false,
+            (Some(value), None) => !value.is_null(),
+            (Some(value), Some((op, literal))) => op.compare(value, literal),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Condition {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Condition, D::Error> {
+        Condition::parse(&String::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+/// A measurement to write the points of a message to, if its payload meets `filter`.
+#[derive(Debug, Deserialize)]
+pub struct Route {
+    pub filter: Condition,
+    pub dst_name: String,
+}
+
+fn default_qos() -> QoS {
+    QoS::AtMostOnce
+}
+
+fn deserialize_qos<'de, D: Deserializer<'de>>(deserializer: D) -> Result<QoS, D::Error> {
+    let qos = u8::deserialize(deserializer)?;
+    rumqttc::qos(qos)
+        .map_err(|_| D::Error::custom(format!("invalid qos {}, expected 0, 1 or 2", qos)))
+}
+
+/// How to tell that a message repeats the previous one on the same topic.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Dedupe {
+    /// The payload is byte for byte the same.
+    Payload,
+    /// The points have the same tags and fields, even if the payload differs otherwise, for
+    /// example in a timestamp.
+    Fields,
+}
+
+/// How `raw_payload` stores the payload.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RawEncoding {
+    /// The payload as text. Messages that are not valid UTF-8 are rejected.
+    #[default]
+    Utf8,
+    /// The payload encoded as base64, for binary payloads.
+    Base64,
+}
+
+/// Where to store the original payload on the points of an entry.
+#[derive(Debug, Deserialize)]
+pub struct RawPayload {
+    dst_name: String,
+    #[serde(default = "DstVariant::default")]
+    dst_variant: DstVariant,
+    #[serde(default)]
+    encoding: RawEncoding,
+}
+
+/// How payloads are encoded on the wire, to be decoded before the fields are extracted.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadEncoding {
+    /// The bytes as they are.
+    #[default]
+    Raw,
+    /// Text of hexadecimal digits, such as `7B7D` for `{}`.
+    Hex,
+    /// Base64 text, with or without padding, in the standard or URL safe alphabet.
+    Base64,
+}
+
+/// Base64 as in `payload_encoding`, padded or not as devices differ in that.
+const BASE64_PAYLOAD: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    PAD.with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+/// The URL safe alphabet, which is accepted as well.
+const BASE64_URL_SAFE_PAYLOAD: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    PAD.with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+impl fmt::Display for PayloadEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PayloadEncoding::Raw => "raw",
+            PayloadEncoding::Hex => "hex",
+            PayloadEncoding::Base64 => "base64",
+        })
+    }
+}
+
+impl PayloadEncoding {
+    /// The payload as it was before it was encoded.
+    pub fn decode<'a>(&self, payload: &'a [u8]) -> Result<Cow<'a, [u8]>, ExtractError> {
+        let decoded = match self {
+            PayloadEncoding::Raw => return Ok(Cow::Borrowed(payload)),
+            PayloadEncoding::Hex => hex::decode(payload),
+            PayloadEncoding::Base64 => {
+                let text = payload.trim_ascii();
+                BASE64_PAYLOAD
+                    .decode(text)
+                    .or_else(|e| BASE64_URL_SAFE_PAYLOAD.decode(text).map_err(|_| e))
+                    .map_err(|e| e.to_string())
+            }
+        };
+        decoded
+            .map(Cow::Owned)
+            .map_err(|reason| ExtractError::InvalidEncoding {
+                encoding: *self,
+                reason,
+                preview: preview(payload),
+            })
+    }
+}
+
+impl RawPayload {
+    fn write_to<'a>(&self, payload: &[u8], point: Point<'a>) -> Result<Point<'a>, ExtractError> {
+        let value = match self.encoding {
+            RawEncoding::Utf8 => decode_text(payload)?.to_owned(),
+            RawEncoding::Base64 => BASE64_STANDARD.encode(payload),
+        };
+        Ok(self
+            .dst_variant
+            .write_to(&self.dst_name, DBValue::String(value.into()), point))
+    }
+}
+
+fn deserialize_regex<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Regex>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| Regex::new(&format!("^(?:{})$", s)).map_err(D::Error::custom))
+        .transpose()
+}
+
+fn deserialize_precision<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Precision>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| match s.as_str() {
+            "s" => Ok(Precision::Seconds),
+            "ms" => Ok(Precision::Milliseconds),
+            "us" => Ok(Precision::Microseconds),
+            "ns" => Ok(Precision::Nanoseconds),
+            s => Err(D::Error::custom(format!(
+                "invalid precision {}, expected s, ms, us or ns",
+                s
+            ))),
+        })
+        .transpose()
+}
+
+fn deserialize_url<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Url, D::Error> {
+    Url::parse(&String::deserialize(deserializer)?).map_err(D::Error::custom)
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| humantime::parse_duration(&s).map_err(D::Error::custom))
+        .transpose()
+}
+
+/// Parse a duration such as `3s` or `-1m 30s` into nanoseconds.
+fn deserialize_offset<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i128, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    let (sign, duration) = match s.trim().strip_prefix('-') {
+        Some(duration) => (-1, duration),
+        None => (1, s.trim().trim_start_matches('+')),
+    };
+    let duration = humantime::parse_duration(duration.trim_start()).map_err(D::Error::custom)?;
+    Ok(sign * duration.as_nanos() as i128)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Entry {
+    /// MQTT topic filter to subscribe to. Wildcards can be named, as in `sensors/+device/#rest`,
+    /// to capture the topic levels they match for use in `topic_tags`.
+    pub src_topic: String,
+    /// Regular expression the whole topic must also match, to refine `src_topic` beyond what
+    /// MQTT wildcards can express. Named capture groups, as in `(?P<device>[0-9a-f]{4})`, are
+    /// added as tags.
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    pub src_topic_regex: Option<Regex>,
+    /// MQTT topic filters, such as `sensors/debug/#`, for topics matching `src_topic` that the
+    /// entry skips anyway.
+    #[serde(default)]
+    pub exclude_topics: Vec<String>,
+    /// Name of the broker in `brokers` to subscribe on, instead of the one given with
+    /// `--mqtt-url`.
+    pub broker: Option<String>,
+    pub dst_name: String,
+    /// Encoding of the payload, such as `base64` for devices that wrap JSON or binary payloads
+    /// in text, decoded before the fields are extracted.
+    #[serde(default)]
+    pub payload_encoding: PayloadEncoding,
+    /// Organization of the bucket to write the points to instead of `--influxdb-org`. Only used
+    /// with InfluxDB 2.
+    pub org: Option<String>,
+    /// Bucket to write the points to instead of `--influxdb-bucket`.
+    pub bucket: Option<String>,
+    /// Timestamp precision to write the points with instead of `--influxdb-precision`, as `s`,
+    /// `ms`, `us` or `ns`.
+    #[serde(default, deserialize_with = "deserialize_precision")]
+    pub precision: Option<Precision>,
+    pub throttle_ms: Option<u64>,
+    /// QoS level used when subscribing to `src_topic`.
+    #[serde(default = "default_qos", deserialize_with = "deserialize_qos")]
+    pub qos: QoS,
+    /// Do not receive messages on `src_topic` that this client published itself. Only
+    /// supported with MQTT v5.
+    #[serde(default)]
+    pub no_local: bool,
+    /// Skip retained messages the server sends again when the bridge subscribes after
+    /// reconnecting, as they were written after the first connect already. Retained messages
+    /// on the first connect are still written.
+    #[serde(default)]
+    pub ignore_retained_on_resubscribe: bool,
+    /// Take the point timestamp from the payload instead of using the server time.
+    pub timestamp: Option<Timestamp>,
+    /// Shift the timestamp taken from the payload by a duration such as `-3s`, in nanoseconds,
+    /// to correct for devices with a skewed clock.
+    #[serde(default, deserialize_with = "deserialize_offset")]
+    pub timestamp_offset: i128,
+    /// Round the timestamp down to a multiple of this duration, e.g. `1m`, using the time of
+    /// receipt without `timestamp`. As InfluxDB keeps only the last point written for a series
+    /// and timestamp, this keeps only the latest reading per interval.
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub timestamp_truncate: Option<Duration>,
+    /// Tags to add from named wildcards in `src_topic`, as a map of wildcard name to tag name.
+    #[serde(default)]
+    pub topic_tags: BTreeMap<String, String>,
+    /// Write at most one message per topic in this interval, e.g. `10s`, dropping the messages
+    /// in between. Unlike `throttle_ms`, which applies to the entry as a whole, this is tracked
+    /// for every topic matching `src_topic` separately.
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub min_interval: Option<Duration>,
+    /// Skip messages that repeat the last message written for the same topic.
+    pub dedupe: Option<Dedupe>,
+    /// Write a repeated message anyway once this long has passed since the last one written,
+    /// e.g. `5m`, so the series does not go silent.
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub dedupe_ttl: Option<Duration>,
+    /// Only write messages whose payload meets this condition. Only supported for `json` entries.
+    pub filter: Option<Condition>,
+    /// Dotted path to the measurement name in the payload, such as `metric`, using `dst_name` if
+    /// it is missing. For `json_array` entries the path is resolved relative to the element.
+    /// Only supported for `json` entries.
+    pub measurement_from: Option<String>,
+    /// Measurements to write to instead of `dst_name` depending on the payload, such as
+    /// `{filter: "level > 100", dst_name: alerts}`. The first route whose filter the payload
+    /// meets is taken. Only supported for `json` entries.
+    #[serde(default)]
+    pub routes: Vec<Route>,
+    /// Skip messages that meet none of the `routes`, rather than writing them to `dst_name`.
+    #[serde(default)]
+    pub drop_unrouted: bool,
+    /// Constant tags added to every point of this entry.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// Constant fields added to every point of this entry, such as `{value: 1}`, for entries that
+    /// would otherwise only get tags from the payload.
+    #[serde(default)]
+    pub constant_fields: BTreeMap<String, Value>,
+    /// What to do with float fields that are NaN or infinite, as parsed from text such as `NaN`
+    /// or `inf`.
+    #[serde(default)]
+    pub on_non_finite: NonFinitePolicy,
+    /// Also store the payload as it was received, to tell exactly what a device sent. Every
+    /// distinct payload stored as a tag adds a series, so this is best kept a field.
+    pub raw_payload: Option<RawPayload>,
+    /// Tag to store the QoS level of the message in, as `0`, `1` or `2`.
+    pub qos_tag: Option<String>,
+    /// Tag to store whether the message was retained in, as `true` or `false`, to tell readings
+    /// the server kept from before subscribing apart from live ones.
+    pub retain_tag: Option<String>,
+    #[serde(flatten)]
+    pub fields: Fields,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.src_topic)?;
+        if let Some(regex) = &self.src_topic_regex {
+            write!(f, " matching {}", regex)?;
+        }
+        if !self.exclude_topics.is_empty() {
+            write!(f, " except {}", self.exclude_topics.join(", "))?;
+        }
+        if let Some(broker) = &self.broker {
+            write!(f, " on broker {}", broker)?;
+        }
+        f.write_str(" -> measurement ")?;
+        for route in &self.routes {
+            write!(f, "{} | ", route.dst_name)?;
+        }
+        match &self.measurement_from {
+            Some(path) => write!(f, "{} or {}", path, self.dst_name)?,
+            None => f.write_str(&self.dst_name)?,
+        }
+        if let Some(bucket) = &self.bucket {
+            write!(f, " in bucket {}", bucket)?;
+        }
+        if let Some(org) = &self.org {
+            write!(f, " of org {}", org)?;
+        }
+        write!(f, ": ")?;
+        if !matches!(self.payload_encoding, PayloadEncoding::Raw) {
+            write!(f, "{} of ", self.payload_encoding)?;
+        }
+        write!(f, "{}", self.fields)?;
+        if let Some(raw_payload) = &self.raw_payload {
+            write!(
+                f,
+                ", payload -> {} {}",
+                raw_payload.dst_variant, raw_payload.dst_name
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Entry {
+    /// The MQTT topic filter for `src_topic`, with the names of named wildcards removed.
+    pub fn src_filter(&self) -> Cow<'_, str> {
+        if !self.src_topic.contains(['+', '#']) {
+            return Cow::Borrowed(&self.src_topic);
+        }
+
+        let levels: Vec<_> = self
+            .src_topic
+            .split('/')
+            .map(|level| match level.chars().next() {
+                Some('+') => "+",
+                Some('#') => "#",
+                _ => level,
+            })
+            .collect();
+        Cow::Owned(levels.join("/"))
+    }
+
+    /// Where to write the points of this entry.
+    pub fn target(&self) -> WriteTarget {
+        WriteTarget {
+            org: self.org.clone(),
+            bucket: self.bucket.clone(),
+            precision: self.precision,
+        }
+    }
+
+    /// Pair the named wildcards in `src_topic` with the levels of `topic` they match.
+    ///
+    /// A named multi-level wildcard captures all remaining levels, joined by `/`.
+    pub fn topic_captures<'e>(&'e self, topic: &str) -> Vec<(&'e str, String)> {
+        let mut levels = topic.split('/');
+        let mut captures = Vec::new();
+
+        for segment in self.src_topic.split('/') {
+            if let Some(name) = segment.strip_prefix('#') {
+                let rest: Vec<_> = levels.by_ref().collect();
+                if !name.is_empty() {
+                    captures.push((name, rest.join("/")));
+                }
+                break;
+            }
+
+            let Some(level) = levels.next() else {
+                break;
+            };
+
+            if let Some(name) = segment.strip_prefix('+').filter(|name| !name.is_empty()) {
+                captures.push((name, level.to_owned()));
+            }
+        }
+
+        captures
+    }
+
+    /// Whether the points may go to another measurement than `dst_name`, or get tags that differ
+    /// from message to message, either of which can tell them apart from the points of other
+    /// entries.
+    fn has_varying_series(&self) -> bool {
+        self.dst_name.contains('{')
+            || self.measurement_from.is_some()
+            || !self.routes.is_empty()
+            || !self.topic_tags.is_empty()
+            || self
+                .src_topic_regex
+                .as_ref()
+                .is_some_and(|regex| regex.capture_names().flatten().next().is_some())
+            || self.fields.has_tags()
+    }
+
+    /// Whether `topic` matches `src_topic` and `src_topic_regex`, and none of `exclude_topics`.
+    pub fn accepts_topic(&self, topic: &str) -> bool {
+        rumqttc::matches(topic, &self.src_filter())
+            && self
+                .src_topic_regex
+                .as_ref()
+                .is_none_or(|regex| regex.is_match(topic))
+            && !self
+                .exclude_topics
+                .iter()
+                .any(|filter| rumqttc::matches(topic, filter))
+    }
+
+    /// The measurement name for a message on `topic`: `dst_name`, with its `{name}` placeholders
+    /// filled in from the named wildcards of `src_topic` and named groups of `src_topic_regex`.
+    pub fn measurement(&self, topic: &str) -> Cow<'_, str> {
+        if !self.dst_name.contains('{') {
+            return Cow::Borrowed(&self.dst_name);
+        }
+
+        let captures = self.topic_captures(topic);
+        let regex_captures = self
+            .src_topic_regex
+            .as_ref()
+            .and_then(|regex| regex.captures(topic));
+        let lookup = |name: &str| {
+            captures
+                .iter()
+                .find(|(wildcard, _)| *wildcard == name)
+                .map(|(_, level)| level.as_str())
+                .or_else(|| Some(regex_captures.as_ref()?.name(name)?.as_str()))
+                .unwrap_or_default()
+        };
+
+        let mut measurement = String::with_capacity(self.dst_name.len());
+        let mut rest = self.dst_name.as_str();
+        // Placeholders were checked when the configuration was loaded.
+        while let Some((literal, placeholder)) = rest.split_once('{') {
+            let (name, after) = placeholder.split_once('}').unwrap();
+            measurement.push_str(literal);
+            measurement.push_str(lookup(name));
+            rest = after;
+        }
+        measurement.push_str(rest);
+        Cow::Owned(measurement)
+    }
+
+    /// Build the points for a message on a topic that matches `src_topic`: one for every record
+    /// of the payload, so none or several for `json_array` entries.
+    ///
+    /// Returns `None` if the payload does not meet the `filter` of the entry, or none of its
+    /// `routes` with `drop_unrouted`.
+    pub fn to_points(
+        &self,
+        message: &Message,
+        precision: Precision,
+    ) -> Result<Option<Vec<Point<'static>>>, ExtractError> {
+        let topic = message.topic.as_str();
+        let decoded = self.payload_encoding.decode(&message.payload)?;
+        let payload = self.fields.decode(&decoded)?;
+
+        if let (Some(filter), Payload::Json(value)) = (&self.filter, &payload) {
+            if !filter.matches(value) {
+                return Ok(None);
+            }
+        }
+
+        let route = match &payload {
+            Payload::Json(value) => self.routes.iter().find(|r| r.filter.matches(value)),
+            Payload::Text(_) | Payload::Bytes(_) => None,
+        };
+        let measurement = match route {
+            Some(route) => route.dst_name.clone(),
+            None if self.drop_unrouted => return Ok(None),
+            None => self.measurement(topic).into_owned(),
+        };
+
+        let mut base = Point::new(measurement);
+        for (name, value) in &self.tags {
+            base = base.add_tag(name, DBValue::String(value.clone().into()));
+        }
+        for (name, value) in &self.constant_fields {
+            base = base.add_field(name, json_to_influxdb(value).unwrap());
+        }
+        if let Some(raw_payload) = &self.raw_payload {
+            base = raw_payload.write_to(&message.payload, base)?;
+        }
+        if let Some(tag) = &self.qos_tag {
+            base = base.add_tag(tag, DBValue::String((message.qos as u8).to_string().into()));
+        }
+        if let Some(tag) = &self.retain_tag {
+            base = base.add_tag(tag, DBValue::String(message.retain.to_string().into()));
+        }
+
+        let mut points = Vec::new();
+        for record in self.fields.records(&payload)? {
+            let mut point = self
+                .on_non_finite
+                .apply(self.fields.extract(&record, base.clone())?)?;
+
+            if let (Some(path), Payload::Json(value)) = (&self.measurement_from, record.as_ref()) {
+                match resolve_path(value, path) {
+                    Some(Value::String(s)) if !s.is_empty() => point.measurement = s.clone(),
+                    Some(value @ (Value::Number(_) | Value::Bool(_))) => {
+                        point.measurement = value.to_string()
+                    }
+                    _ => log::debug!("{} not found in payload, using {}", path, self.dst_name),
+                }
+            }
+
+            if !self.topic_tags.is_empty() {
+                for (name, level) in self.topic_captures(topic) {
+                    if let Some(tag) = self.topic_tags.get(name) {
+                        point = point.add_tag(tag, DBValue::String(level.into()));
+                    }
+                }
+            }
+
+            if let Some(regex) = &self.src_topic_regex {
+                if let Some(captures) = regex.captures(topic) {
+                    for name in regex.capture_names().flatten() {
+                        if let Some(m) = captures.name(name) {
+                            point =
+                                point.add_tag(name, DBValue::String(m.as_str().to_owned().into()));
+                        }
+                    }
+                }
+            }
+
+            if let Some(nanos) = self.timestamp(&record, message) {
+                point = point.add_timestamp(timestamp_in(nanos, precision));
+            }
+            points.push(point);
+        }
+
+        Ok(Some(points))
+    }
+
+    /// The timestamp in nanoseconds for a record, or `None` to leave it to InfluxDB.
+    fn timestamp(&self, record: &Payload, message: &Message) -> Option<i128> {
+        let nanos = match &self.timestamp {
+            Some(timestamp) => match timestamp.resolve(record, &message.user_properties) {
+                Ok(nanos) => nanos.map(|nanos| nanos + self.timestamp_offset),
+                Err(e) => {
+                    log::warn!("Using server time for {}: {}", self.dst_name, e);
+                    None
+                }
+            },
+            None => None,
+        };
+        match self.timestamp_truncate {
+            Some(interval) => {
+                let nanos = nanos.unwrap_or_else(|| {
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default();
+                    now.as_nanos() as i128
+                });
+                Some(nanos - nanos.rem_euclid(interval.as_nanos() as i128))
+            }
+            None => nanos,
+        }
+    }
+}
+
+/// Another MQTT server to subscribe to, besides the one given with `--mqtt-url`. All other
+/// `--mqtt-*` options apply to it as well.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Broker {
+    #[serde(deserialize_with = "deserialize_url")]
+    pub url: Url,
+    /// Client ID to identify with instead of `--mqtt-client-id`.
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Configuration {
+    /// Constant tags added to the points of all entries. Tags of an entry with the same name
+    /// take precedence.
+    #[serde(default)]
+    pub global_tags: BTreeMap<String, String>,
+    /// Additional MQTT servers by name, for entries to subscribe to with `broker`.
+    #[serde(default)]
+    pub brokers: BTreeMap<String, Broker>,
+    pub entries: Vec<Entry>,
+}
+
+/// Whether `path` is an `http://` or `https://` URL rather than a file.
+pub fn is_url(path: &Path) -> bool {
+    path.to_str()
+        .is_some_and(|s| s.starts_with("http://") || s.starts_with("https://"))
+}
+
+async fn fetch(url: &str) -> Result<Vec<u8>, reqwest::Error> {
+    let client = reqwest::Client::builder().timeout(FETCH_TIMEOUT).build()?;
+    let response = client.get(url).send().await?.error_for_status()?;
+    Ok(response.bytes().await?.to_vec())
+}
+
+/// Read the contents of the file at `path`, of standard input for `-`, or of a URL.
+async fn read(path: &Path) -> Result<Vec<u8>, ConfigError> {
+    let io_error = |source| ConfigError::Io {
+        path: path.to_owned(),
+        source,
+    };
+
+    if path == Path::new("-") {
+        let mut contents = Vec::new();
+        std::io::stdin()
+            .read_to_end(&mut contents)
+            .map_err(io_error)?;
+        Ok(contents)
+    } else if is_url(path) {
+        // Checked by `is_url`.
+        fetch(path.to_str().unwrap())
+            .await
+            .map_err(|source| ConfigError::Fetch {
+                path: path.to_owned(),
+                source,
+            })
+    } else {
+        std::fs::read(path).map_err(io_error)
+    }
+}
+
+impl Configuration {
+    /// Read, parse and validate the configuration at `path`, which is a file, `-` for standard
+    /// input, or an `http://` or `https://` URL.
+    pub async fn load(path: &Path) -> Result<Configuration, ConfigError> {
+        let contents = read(path).await?;
+
+        let configuration: Configuration =
+            serde_yaml::from_slice(&contents).map_err(|source| ConfigError::Syntax {
+                path: path.to_owned(),
+                location: source.location(),
+                source,
+            })?;
+
+        configuration
+            .validate()
+            .map_err(|reason| ConfigError::Invalid {
+                path: path.to_owned(),
+                reason,
+            })?;
+
+        Ok(configuration)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.global_tags.keys().any(String::is_empty) {
+            return Err("global_tags: tag names must not be empty".to_owned());
+        }
+
+        for (i, entry) in self.entries.iter().enumerate() {
+            if entry.src_topic.is_empty() {
+                return Err(format!("entries[{}]: src_topic must not be empty", i));
+            }
+
+            if !rumqttc::valid_filter(&entry.src_filter()) {
+                return Err(format!(
+                    "entries[{}]: invalid src_topic filter {:?}",
+                    i, entry.src_topic
+                ));
+            }
+
+            if let Some(filter) = entry
+                .exclude_topics
+                .iter()
+                .find(|filter| filter.is_empty() || !rumqttc::valid_filter(filter))
+            {
+                return Err(format!(
+                    "entries[{}]: invalid exclude_topics filter {:?}",
+                    i, filter
+                ));
+            }
+
+            if entry.dst_name.is_empty() {
+                return Err(format!("entries[{}]: dst_name must not be empty", i));
+            }
+
+            if let Some(broker) = &entry.broker {
+                if !self.brokers.contains_key(broker) {
+                    return Err(format!("entries[{}]: unknown broker {:?}", i, broker));
+                }
+            }
+
+            if entry.timestamp_truncate == Some(Duration::ZERO) {
+                return Err(format!(
+                    "entries[{}]: timestamp_truncate must not be zero",
+                    i
+                ));
+            }
+
+            if entry.bucket.as_deref() == Some("") {
+                return Err(format!("entries[{}]: bucket must not be empty", i));
+            }
+
+            if entry.org.as_deref() == Some("") {
+                return Err(format!("entries[{}]: org must not be empty", i));
+            }
+
+            let captures: Vec<_> = entry
+                .src_topic
+                .split('/')
+                .filter_map(|level| level.strip_prefix(['+', '#']))
+                .collect();
+            if let Some(name) = entry
+                .topic_tags
+                .keys()
+                .find(|name| !captures.contains(&name.as_str()))
+            {
+                return Err(format!(
+                    "entries[{}]: topic_tags refers to {:?}, which is not a named wildcard in src_topic",
+                    i, name
+                ));
+            }
+
+            for name in placeholders(&entry.dst_name)
+                .map_err(|e| format!("entries[{}]: dst_name: {}", i, e))?
+            {
+                let in_regex = entry
+                    .src_topic_regex
+                    .as_ref()
+                    .is_some_and(|regex| regex.capture_names().flatten().any(|n| n == name));
+                if !captures.contains(&name) && !in_regex {
+                    return Err(format!(
+                        "entries[{}]: dst_name refers to {:?}, which is not a named wildcard in src_topic or a named group in src_topic_regex",
+                        i, name
+                    ));
+                }
+            }
+
+            if entry.tags.keys().any(String::is_empty) {
+                return Err(format!("entries[{}]: tag names must not be empty", i));
+            }
+
+            if entry.qos_tag.as_deref() == Some("") || entry.retain_tag.as_deref() == Some("") {
+                return Err(format!("entries[{}]: tag names must not be empty", i));
+            }
+
+            if entry
+                .raw_payload
+                .as_ref()
+                .is_some_and(|raw_payload| raw_payload.dst_name.is_empty())
+            {
+                return Err(format!(
+                    "entries[{}]: raw_payload.dst_name must not be empty",
+                    i
+                ));
+            }
+
+            for (name, value) in &entry.constant_fields {
+                if name.is_empty() {
+                    return Err(format!(
+                        "entries[{}]: constant_fields names must not be empty",
+                        i
+                    ));
+                }
+                if !matches!(value, Value::Bool(_) | Value::Number(_) | Value::String(_)) {
+                    return Err(format!(
+                        "entries[{}]: constant_fields.{} must be a string, number or boolean",
+                        i, name
+                    ));
+                }
+            }
+            let raw_payload_field = entry
+                .raw_payload
+                .as_ref()
+                .is_some_and(|raw_payload| matches!(raw_payload.dst_variant, DstVariant::Field));
+            if !entry.fields.has_fields() && entry.constant_fields.is_empty() && !raw_payload_field
+            {
+                return Err(format!(
+                    "entries[{}]: points would only have tags, which InfluxDB rejects; add a \
+                     field such as `constant_fields: {{value: 1}}`",
+                    i
+                ));
+            }
+
+            match &entry.fields {
+                Fields::SingleText { dst_name, .. } if dst_name.is_empty() => {
+                    return Err(format!(
+                        "entries[{}]: single_text value_name must not be empty",
+                        i
+                    ));
+                }
+                Fields::SingleText {
+                    bool_map: Some(_),
+                    dst_type,
+                    transform,
+                    ..
+                } if dst_type.is_some() || !transform.is_identity() => {
+                    return Err(format!(
+                        "entries[{}]: bool_map cannot be combined with dst_type, scale or offset",
+                        i
+                    ));
+                }
+                Fields::SingleText {
+                    infer_type: true,
+                    bool_map,
+                    dst_type,
+                    ..
+                } if bool_map.is_some() || dst_type.is_some() => {
+                    return Err(format!(
+                        "entries[{}]: infer_type cannot be combined with bool_map or dst_type",
+                        i
+                    ));
+                }
+                Fields::Json { fields, .. } | Fields::JsonArray { fields, .. } => {
+                    for (j, field) in fields.iter().enumerate() {
+                        if field.src_path.as_str().is_empty() {
+                            return Err(format!(
+                                "entries[{}].fields[{}]: src_path must not be empty",
+                                i, j
+                            ));
+                        }
+                        if let Some(dst_name) = &field.dst_name {
+                            if dst_name.is_empty() {
+                                return Err(format!(
+                                    "entries[{}].fields[{}]: dst_name must not be empty",
+                                    i, j
+                                ));
+                            }
+                            placeholders(dst_name).map_err(|e| {
+                                format!("entries[{}].fields[{}]: dst_name: {}", i, j, e)
+                            })?;
+                        }
+                    }
+                }
+                Fields::JsonFlatten {
+                    max_depth: Some(0), ..
+                } => {
+                    return Err(format!("entries[{}]: max_depth must not be zero", i));
+                }
+                Fields::Csv { columns, .. } => {
+                    if let Some(j) = columns.iter().position(|c| c.dst_name.is_empty()) {
+                        return Err(format!(
+                            "entries[{}].columns[{}]: dst_name must not be empty",
+                            i, j
+                        ));
+                    }
+                }
+                Fields::Binary { fields, .. } => {
+                    if let Some(j) = fields.iter().position(|f| f.dst_name.is_empty()) {
+                        return Err(format!(
+                            "entries[{}].fields[{}]: dst_name must not be empty",
+                            i, j
+                        ));
+                    }
+                    if entry
+                        .timestamp
+                        .as_ref()
+                        .is_some_and(|t| t.user_property.is_none() || t.src_path.is_some())
+                    {
+                        return Err(format!(
+                            "entries[{}]: timestamp is only supported from a user_property for \
+                             binary entries",
+                            i
+                        ));
+                    }
+                }
+                _ => {}
+            }
+
+            if entry.measurement_from.as_deref() == Some("") {
+                return Err(format!(
+                    "entries[{}]: measurement_from must not be empty",
+                    i
+                ));
+            }
+
+            if entry.measurement_from.is_some()
+                && !matches!(
+                    entry.fields,
+                    Fields::Json { .. }
+                        | Fields::JsonArray { .. }
+                        | Fields::JsonAll { .. }
+                        | Fields::JsonFlatten { .. }
+                )
+            {
+                return Err(format!(
+                    "entries[{}]: measurement_from is only supported for json entries",
+                    i
+                ));
+            }
+
+            if entry.routes.iter().any(|route| route.dst_name.is_empty()) {
+                return Err(format!(
+                    "entries[{}]: routes: dst_name must not be empty",
+                    i
+                ));
+            }
+
+            if !entry.routes.is_empty()
+                && !matches!(
+                    entry.fields,
+                    Fields::Json { .. }
+                        | Fields::JsonArray { .. }
+                        | Fields::JsonAll { .. }
+                        | Fields::JsonFlatten { .. }
+                )
+            {
+                return Err(format!(
+                    "entries[{}]: routes are only supported for json entries",
+                    i
+                ));
+            }
+
+            if entry.filter.is_some()
+                && !matches!(
+                    entry.fields,
+                    Fields::Json { .. }
+                        | Fields::JsonArray { .. }
+                        | Fields::JsonAll { .. }
+                        | Fields::JsonFlatten { .. }
+                )
+            {
+                return Err(format!(
+                    "entries[{}]: filter is only supported for json entries",
+                    i
+                ));
+            }
+
+            if let Some(timestamp) = &entry.timestamp {
+                if timestamp.src_path.is_none()
+                    && timestamp.user_property.is_none()
+                    && !matches!(entry.fields, Fields::SingleText { .. })
+                {
+                    return Err(format!(
+                        "entries[{}]: timestamp.src_path or timestamp.user_property is required \
+                         for json and csv entries",
+                        i
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Describe suspicious but valid parts of the configuration.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        // Entries that write to the same series, as nothing but their fields tells them apart.
+        let mut series = BTreeMap::<_, Vec<usize>>::new();
+        for (i, entry) in self.entries.iter().enumerate() {
+            if !entry.has_varying_series() {
+                let key = (&entry.org, &entry.bucket, &entry.dst_name, &entry.tags);
+                series.entry(key).or_default().push(i);
+            }
+
+            if entry.drop_unrouted && entry.routes.is_empty() {
+                warnings.push(format!(
+                    "entries[{}]: drop_unrouted without routes drops every message",
+                    i
+                ));
+            }
+            if entry.dedupe_ttl.is_some() && entry.dedupe.is_none() {
+                warnings.push(format!(
+                    "entries[{}]: dedupe_ttl has no effect without dedupe",
+                    i
+                ));
+            }
+            if entry.timestamp_offset != 0 && entry.timestamp.is_none() {
+                warnings.push(format!(
+                    "entries[{}]: timestamp_offset has no effect without timestamp",
+                    i
+                ));
+            }
+            if let Some(RawPayload {
+                dst_variant: DstVariant::Tag,
+                ..
+            }) = entry.raw_payload
+            {
+                warnings.push(format!(
+                    "entries[{}]: raw_payload as a tag adds a series for every distinct payload",
+                    i
+                ));
+            }
+        }
+        for name in self.brokers.keys() {
+            if !self.entries.iter().any(|e| e.broker.as_ref() == Some(name)) {
+                warnings.push(format!("broker {:?} is not used by any entry", name));
+            }
+        }
+        for ((_, _, dst_name, _), entries) in series {
+            if entries.len() > 1 {
+                warnings.push(format!(
+                    "entries {:?} all write to the same series of measurement {:?}, as no tags \
+                     tell their points apart",
+                    entries, dst_name
+                ));
+            }
+        }
+
+        warnings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(yaml: &str) -> Entry {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn message(topic: &str, payload: &[u8]) -> Message {
+        Message {
+            topic: topic.to_owned(),
+            payload: payload.to_vec().into(),
+            qos: QoS::AtMostOnce,
+            retain: false,
+            subscription_ids: Vec::new(),
+            user_properties: Vec::new(),
+        }
+    }
+
+    fn try_points(
+        entry: &Entry,
+        payload: &[u8],
+    ) -> Result<Option<Vec<Point<'static>>>, ExtractError> {
+        entry.to_points(&message("sensors/a", payload), Precision::Nanoseconds)
+    }
+
+    fn points(entry: &Entry, payload: &[u8]) -> Vec<Point<'static>> {
+        try_points(entry, payload).unwrap().unwrap()
+    }
+
+    fn json(s: &str) -> Option<DBValue<'static>> {
+        json_to_influxdb(&serde_json::from_str(s).unwrap())
+    }
+
+    #[test]
+    fn base64_payloads() {
+        let entry = entry(
+            "{src_topic: sensors/+, dst_name: m, type: json, payload_encoding: base64, \
+             fields: [{src_path: t}]}",
+        );
+        // `{"t":1}`, padded, unpadded and surrounded by whitespace.
+        for payload in [&b"eyJ0IjoxfQ=="[..], b"eyJ0IjoxfQ", b" eyJ0IjoxfQ==\n"] {
+            assert_eq!(points(&entry, payload)[0].fields["t"], DBValue::Integer(1));
+        }
+        // `{"t":"??>"}`, which differs between the alphabets.
+        for payload in [&b"eyJ0IjoiPz8+In0="[..], b"eyJ0IjoiPz8-In0="] {
+            assert_eq!(
+                points(&entry, payload)[0].fields["t"],
+                DBValue::String("??>".into())
+            );
+        }
+        assert!(matches!(
+            try_points(&entry, b"eyJ0Ijox!Q=="),
+            Err(ExtractError::InvalidEncoding { .. })
+        ));
+    }
+
+    #[test]
+    fn raw_payload() {
+        let with_encoding = |encoding: &str| {
+            entry(&format!(
+                "{{src_topic: sensors/+, dst_name: m, type: json, fields: [{{src_path: t}}], \
+                 raw_payload: {{dst_name: raw, encoding: {}}}}}",
+                encoding
+            ))
+        };
+        let payload = br#"{"t":1}"#;
+        assert_eq!(
+            points(&with_encoding("utf8"), payload)[0].fields["raw"],
+            DBValue::String(r#"{"t":1}"#.into())
+        );
+        assert_eq!(
+            points(&with_encoding("base64"), payload)[0].fields["raw"],
+            DBValue::String("eyJ0IjoxfQ==".into())
+        );
+    }
+
+    #[test]
+    fn whole_timestamps_are_exact() {
+        let millis = entry(
+            "{src_topic: sensors/+, dst_name: m, type: json, fields: [{src_path: v}], \
+             timestamp: {src_path: ts, format: unix_millis}}",
+        );
+        let seconds = entry(
+            "{src_topic: sensors/+, dst_name: m, type: json, fields: [{src_path: v}], \
+             timestamp: {src_path: ts, format: unix_seconds}}",
+        );
+        let timestamp = |entry: &Entry, payload: &[u8], precision| {
+            entry
+                .to_points(&message("sensors/a", payload), precision)
+                .unwrap()
+                .unwrap()[0]
+                .timestamp
+        };
+
+        for payload in [
+            &br#"{"v": 1, "ts": 1700000000123}"#[..],
+            br#"{"v": 1, "ts": "1700000000123"}"#,
+        ] {
+            assert_eq!(
+                timestamp(&millis, payload, Precision::Milliseconds),
+                Some(1_700_000_000_123)
+            );
+            assert_eq!(
+                timestamp(&millis, payload, Precision::Nanoseconds),
+                Some(1_700_000_000_123_000_000)
+            );
+        }
+        assert_eq!(
+            timestamp(
+                &seconds,
+                br#"{"v": 1, "ts": 1700000001}"#,
+                Precision::Nanoseconds
+            ),
+            Some(1_700_000_001_000_000_000)
+        );
+
+        // Fractions are rounded rather than truncated.
+        assert_eq!(
+            timestamp(
+                &seconds,
+                br#"{"v": 1, "ts": 1700000000.123}"#,
+                Precision::Milliseconds
+            ),
+            Some(1_700_000_000_123)
+        );
+        assert_eq!(
+            timestamp(
+                &millis,
+                br#"{"v": 1, "ts": "1700000000123.5"}"#,
+                Precision::Microseconds
+            ),
+            Some(1_700_000_000_123_500)
+        );
+    }
+
+    #[test]
+    fn integers_stay_integers() {
+        assert_eq!(json("42"), Some(DBValue::Integer(42)));
+        assert_eq!(json("-42"), Some(DBValue::Integer(-42)));
+        assert_eq!(
+            json("9007199254740993"),
+            Some(DBValue::Integer(9007199254740993))
+        );
+        assert_eq!(
+            json("-9223372036854775808"),
+            Some(DBValue::Integer(i64::MIN))
+        );
+        assert_eq!(json("21.5"), Some(DBValue::Float(21.5)));
+        assert_eq!(json("-0.25"), Some(DBValue::Float(-0.25)));
+        assert_eq!(json("1e3"), Some(DBValue::Float(1000.0)));
+        assert_eq!(json("null"), None);
+    }
+
+    #[test]
+    fn null_as_zero_keeps_the_type_of_the_field() {
+        let zero = |fields: &str, payloads: &[&[u8]]| {
+            let entry = entry(&format!(
+                "{{src_topic: sensors/+, dst_name: m, type: json, fields: [{}]}}",
+                fields
+            ));
+            // Only the point for the last payload is of interest.
+            let points: Vec<_> = payloads
+                .iter()
+                .map(|payload| points(&entry, payload))
+                .collect();
+            points.last().unwrap()[0].fields["v"].clone()
+        };
+
+        let null: &[u8] = br#"{"v": null}"#;
+        assert_eq!(
+            zero("{src_path: v, on_null: Zero}", &[br#"{"v": 3}"#, null]),
+            DBValue::Integer(0)
+        );
+        assert_eq!(
+            zero("{src_path: v, on_null: Zero}", &[br#"{"v": 3.5}"#, null]),
+            DBValue::Float(0.0)
+        );
+        assert_eq!(
+            zero("{src_path: v, on_null: Zero, dst_type: Integer}", &[null]),
+            DBValue::Integer(0)
+        );
+        assert_eq!(
+            zero("{src_path: v, on_null: Zero, dst_type: Boolean}", &[null]),
+            DBValue::Boolean(false)
+        );
+        assert_eq!(
+            zero("{src_path: v, on_null: Zero}", &[null]),
+            DBValue::Float(0.0)
+        );
+    }
+
+    #[test]
+    fn unsigned_integers() {
+        assert_eq!(
+            json("9223372036854775807"),
+            Some(DBValue::Integer(i64::MAX))
+        );
+        assert_eq!(
+            json("9223372036854775808"),
+            Some(DBValue::UnsignedInteger(i64::MAX as u64 + 1))
+        );
+        assert_eq!(
+            json("18446744073709551615"),
+            Some(DBValue::UnsignedInteger(u64::MAX))
+        );
+        assert_eq!(
+            json("18446744073709551616"),
+            Some(DBValue::Float(18446744073709551616.0))
+        );
+    }
+
+    #[test]
+    fn dst_type_unsigned() {
+        let coerce = |value: Value| DstType::Unsigned.coerce(&value);
+        assert_eq!(
+            coerce(Value::from(u64::MAX)),
+            Some(DBValue::UnsignedInteger(u64::MAX))
+        );
+        assert_eq!(
+            coerce(Value::from("18446744073709551615")),
+            Some(DBValue::UnsignedInteger(u64::MAX))
+        );
+        assert_eq!(coerce(Value::from(42)), Some(DBValue::UnsignedInteger(42)));
+        assert_eq!(
+            coerce(Value::from(42.0)),
+            Some(DBValue::UnsignedInteger(42))
+        );
+        assert_eq!(coerce(Value::from(-1)), None);
+        assert_eq!(coerce(Value::from("18446744073709551616")), None);
+        assert_eq!(coerce(Value::from(1.5)), None);
+    }
+
+    #[test]
+    fn jsonpath_fields() {
+        let entry = entry(
+            "{src_topic: sensors/+, dst_name: m, type: json, fields: [\
+             {src_path: \"$['a.b']\", path_syntax: jsonpath, dst_name: ab}, \
+             {src_path: \"$.items[?(@.id == 'x')].v\", path_syntax: jsonpath, dst_name: v}]}",
+        );
+        let points = points(
+            &entry,
+            br#"{"a.b": 1, "items": [{"id": "y", "v": 2}, {"id": "x", "v": 3}]}"#,
+        );
+        assert_eq!(points[0].fields["ab"], DBValue::Integer(1));
+        assert_eq!(points[0].fields["v"], DBValue::Integer(3));
+    }
+
+    #[test]
+    fn invalid_jsonpath_is_rejected_on_load() {
+        let result = serde_yaml::from_str::<Entry>(
+            "{src_topic: a, dst_name: m, type: json, \
+             fields: [{src_path: 'items[0]', path_syntax: jsonpath}]}",
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn overlapping_entries_all_write() {
+        let configuration: Configuration = serde_yaml::from_str(
+            "entries:\n\
+             - {src_topic: '#', dst_name: all, type: single_text}\n\
+             - {src_topic: sensors/+, dst_name: sensors, type: json, fields: [{src_path: t}]}\n\
+             - {src_topic: other/#, dst_name: other, type: single_text}\n",
+        )
+        .unwrap();
+        configuration.validate().unwrap();
+
+        let message = message("sensors/a", br#"{"t": 21}"#);
+        let measurements: Vec<_> = configuration
+            .entries
+            .iter()
+            .filter(|entry| entry.accepts_topic(&message.topic))
+            .flat_map(|entry| {
+                entry
+                    .to_points(&message, Precision::Nanoseconds)
+                    .unwrap()
+                    .unwrap()
+            })
+            .map(|point| point.measurement)
+            .collect();
+        assert_eq!(measurements, ["all", "sensors"]);
+    }
+
+    #[test]
+    fn invalid_utf8_text_is_an_error() {
+        let entry = entry("{src_topic: sensors/+, dst_name: m, type: single_text}");
+        assert!(matches!(
+            try_points(&entry, b"ok \xff\xfe"),
+            Err(ExtractError::InvalidUtf8 { len: 5 })
+        ));
+        assert_eq!(
+            points(&entry, "ok ✓".as_bytes())[0].fields["value"],
+            DBValue::String("ok ✓".into())
+        );
+    }
+
+    #[test]
+    fn broken_json_is_an_error() {
+        let entry =
+            entry("{src_topic: sensors/+, dst_name: m, type: json, fields: [{src_path: t}]}");
+        for payload in [&br#"{"t": 21"#[..], b"booting...", b""] {
+            match try_points(&entry, payload) {
+                Err(ExtractError::InvalidJson { preview: text, .. }) => {
+                    assert_eq!(text, preview(payload))
+                }
+                result => panic!("unexpected result {:?} for {:?}", result, payload),
+            }
+        }
+    }
+
+    #[test]
+    fn scale_and_offset() {
+        let entry = entry(
+            "{src_topic: sensors/+, dst_name: m, type: json, fields: [\
+             {src_path: temp, scale: 0.1}, \
+             {src_path: fahrenheit, scale: 0.5, offset: -16}, \
+             {src_path: count, scale: 2, dst_type: Integer}, \
+             {src_path: state, scale: 0.1}]}",
+        );
+        let fields = &points(&entry, br#"{"temp": 235, "fahrenheit": 72, "count": 21}"#)[0].fields;
+        match fields["temp"] {
+            DBValue::Float(x) => assert!((x - 23.5).abs() < 1e-9),
+            ref value => panic!("unexpected value {:?}", value),
+        }
+        // Scaled first, then offset.
+        assert_eq!(fields["fahrenheit"], DBValue::Float(20.0));
+        // Transformed first, then converted.
+        assert_eq!(fields["count"], DBValue::Integer(42));
+
+        assert!(matches!(
+            try_points(&entry, br#"{"temp": 235, "state": "on"}"#),
+            Err(ExtractError::NotNumeric { .. })
+        ));
+    }
+
+    #[test]
+    fn missing_path_segments_skip_the_field() {
+        let payload: Value = serde_json::from_str(r#"{"a": {"x": 1}, "l": [10, 20]}"#).unwrap();
+        assert_eq!(resolve_path(&payload, "a.x"), Some(&Value::from(1)));
+        assert_eq!(resolve_path(&payload, "l.1"), Some(&Value::from(20)));
+        assert_eq!(resolve_path(&payload, "a.missing.b"), None);
+        assert_eq!(resolve_path(&payload, "a.x.b"), None);
+        assert_eq!(resolve_path(&payload, "a.missing"), None);
+        assert_eq!(resolve_path(&payload, "l.2"), None);
+        assert_eq!(resolve_path(&payload, "l.x"), None);
+
+        let entry = entry(
+            "{src_topic: sensors/+, dst_name: m, type: json, fields: [\
+             {src_path: a.x}, {src_path: a.missing.b}, {src_path: a.missing}]}",
+        );
+        let fields = &points(&entry, br#"{"a": {"x": 1}}"#)[0].fields;
+        assert_eq!(fields.len(), 1);
+        assert_eq!(fields["a.x"], DBValue::Integer(1));
+    }
+
+    #[test]
+    fn non_finite_floats() {
+        let with_policy = |policy: &str| {
+            entry(&format!(
+                "{{src_topic: sensors/+, dst_name: m, type: single_text, dst_type: Float, \
+                 on_non_finite: {}}}",
+                policy
+            ))
+        };
+        for payload in [&b"NaN"[..], b"inf", b"-inf", b"Infinity"] {
+            assert!(points(&with_policy("Skip"), payload)[0].fields.is_empty());
+            assert_eq!(
+                points(&with_policy("Zero"), payload)[0].fields["value"],
+                DBValue::Float(0.0)
+            );
+            assert!(matches!(
+                try_points(&with_policy("Error"), payload),
+                Err(ExtractError::NonFinite { .. })
+            ));
+        }
+        assert_eq!(
+            points(&with_policy("Error"), b"1.5")[0].fields["value"],
+            DBValue::Float(1.5)
+        );
+
+        // Finite values can be transformed into infinite ones.
+        let entry = entry(
+            "{src_topic: sensors/+, dst_name: m, type: json, fields: [{src_path: v, scale: 10}]}",
+        );
+        assert!(points(&entry, br#"{"v": 1e308}"#)[0].fields.is_empty());
+    }
+
+    #[test]
+    fn to_points_single() {
+        let entry = entry(
+            "{src_topic: sensors/+, dst_name: m, tags: {site: home}, type: json, \
+             fields: [{src_path: t}]}",
+        );
+        let points = points(&entry, br#"{"t": 21.5}"#);
+
+        assert_eq!(points.len(), 1);
+        assert_eq!(points[0].measurement, "m");
+        assert_eq!(points[0].tags["site"], DBValue::String("home".into()));
+        assert_eq!(points[0].fields["t"], DBValue::Float(21.5));
+        assert_eq!(points[0].timestamp, None);
+    }
+
+    #[test]
+    fn to_points_none() {
+        let entry = entry(
+            "{src_topic: sensors/+, dst_name: m, type: json_array, src_path: readings, \
+             fields: [{src_path: v}]}",
+        );
+        assert!(points(&entry, br#"{"readings": []}"#).is_empty());
+    }
+
+    #[test]
+    fn to_points_many() {
+        let entry = entry(
+            "{src_topic: sensors/+, dst_name: m, type: json_array, src_path: readings, \
+             fields: [{src_path: v}]}",
+        );
+        let points = points(
+            &entry,
+            br#"{"readings": [{"v": 10}, {"v": 20}, {"v": 30}]}"#,
+        );
+
+        let values: Vec<_> = points.iter().map(|point| &point.fields["v"]).collect();
+        assert_eq!(
+            values,
+            [
+                &DBValue::Integer(10),
+                &DBValue::Integer(20),
+                &DBValue::Integer(30)
+            ]
+        );
+    }
+
+    #[test]
+    fn to_points_filtered() {
+        let entry = entry(
+            "{src_topic: sensors/+, dst_name: m, filter: 'status == \"ok\"', type: json, \
+             fields: [{src_path: t}]}",
+        );
+        let message = message("sensors/a", br#"{"status": "error", "t": 1}"#);
+        assert!(entry
+            .to_points(&message, Precision::Nanoseconds)
+            .unwrap()
+            .is_none());
+    }
 }
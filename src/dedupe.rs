@@ -0,0 +1,143 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::{config::Dedupe, point::Point};
+
+enum Seen {
+    Payload(Vec<u8>),
+    /// The points, without their timestamps.
+    Points(Vec<Point<'static>>),
+}
+
+/// Whether two points have the same measurement, tags and fields.
+fn same_point(a: &Point, b: &Point) -> bool {
+    a.measurement == b.measurement && a.tags == b.tags && a.fields == b.fields
+}
+
+/// Remembers the last message written for every entry and topic, to skip repeats.
+#[derive(Default)]
+pub struct Deduplicator {
+    last: HashMap<(usize, String), (Seen, Instant)>,
+}
+
+impl Deduplicator {
+    /// Whether the message on `topic` for entry `index` repeats the last one written and not
+    /// more than `ttl` ago. If not, it is remembered as the last one written.
+    pub fn is_duplicate(
+        &mut self,
+        index: usize,
+        topic: &str,
+        dedupe: Dedupe,
+        ttl: Option<Duration>,
+        payload: &[u8],
+        points: &[Point<'static>],
+    ) -> bool {
+        let now = Instant::now();
+        let key = (index, topic.to_owned());
+
+        if let Some((seen, at)) = self.last.get(&key) {
+            let same = match seen {
+                Seen::Payload(last) => last == payload,
+                Seen::Points(last) => {
+                    last.len() == points.len()
+                        && last.iter().zip(points).all(|(a, b)| same_point(a, b))
+                }
+            };
+            if same && ttl.is_none_or(|ttl| now.duration_since(*at) < ttl) {
+                return true;
+            }
+        }
+
+        let seen = match dedupe {
+            Dedupe::Payload => Seen::Payload(payload.to_vec()),
+            Dedupe::Fields => Seen::Points(
+                points
+                    .iter()
+                    .map(|point| Point {
+                        timestamp: None,
+                        ..point.clone()
+                    })
+                    .collect(),
+            ),
+        };
+        self.last.insert(key, (seen, now));
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::point::Value;
+
+    fn point(value: f64, timestamp: i64) -> Point<'static> {
+        Point::new("m")
+            .add_tag("site", Value::String("home".into()))
+            .add_field("t", Value::Float(value))
+            .add_timestamp(timestamp)
+    }
+
+    #[test]
+    fn payload_mode() {
+        let mut dedupe = Deduplicator::default();
+        let points = [point(21.5, 1)];
+        let check = |dedupe: &mut Deduplicator, payload: &[u8]| {
+            dedupe.is_duplicate(0, "sensors/a", Dedupe::Payload, None, payload, &points)
+        };
+
+        assert!(!check(&mut dedupe, br#"{"t": 21.5}"#));
+        assert!(check(&mut dedupe, br#"{"t": 21.5}"#));
+        // The same value, written differently.
+        assert!(!check(&mut dedupe, br#"{"t":21.5}"#));
+        assert!(check(&mut dedupe, br#"{"t":21.5}"#));
+    }
+
+    #[test]
+    fn fields_mode_ignores_timestamps() {
+        let mut dedupe = Deduplicator::default();
+        let mut check = |payload: &[u8], points: &[Point<'static>]| {
+            dedupe.is_duplicate(0, "sensors/a", Dedupe::Fields, None, payload, points)
+        };
+
+        assert!(!check(br#"{"t": 21.5, "ts": 1}"#, &[point(21.5, 1)]));
+        assert!(check(br#"{"t": 21.5, "ts": 2}"#, &[point(21.5, 2)]));
+        assert!(!check(br#"{"t": 21.6, "ts": 3}"#, &[point(21.6, 3)]));
+        assert!(!check(b"", &[point(21.6, 4), point(21.7, 4)]));
+        assert!(!check(b"", &[point(21.6, 5)]));
+        assert!(!check(
+            b"",
+            &[Point::new("m").add_field("t", Value::Float(21.6))]
+        ));
+    }
+
+    #[test]
+    fn repeats_are_written_after_the_ttl() {
+        let ttl = Some(Duration::from_millis(50));
+        let mut dedupe = Deduplicator::default();
+        let mut check = || dedupe.is_duplicate(0, "sensors/a", Dedupe::Payload, ttl, b"21.5", &[]);
+
+        assert!(!check());
+        assert!(check());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!check());
+        // The repeat that was written starts the interval again.
+        assert!(check());
+    }
+
+    #[test]
+    fn keyed_by_entry_and_topic() {
+        let mut dedupe = Deduplicator::default();
+        let mut check = |index, topic: &str| {
+            dedupe.is_duplicate(index, topic, Dedupe::Payload, None, b"21.5", &[])
+        };
+
+        assert!(!check(0, "sensors/a"));
+        assert!(!check(0, "sensors/b"));
+        assert!(!check(1, "sensors/a"));
+        assert!(check(0, "sensors/a"));
+        assert!(check(0, "sensors/b"));
+        assert!(check(1, "sensors/a"));
+    }
+}
@@ -0,0 +1,131 @@
+//! Watching the configuration file for changes.
+//!
+//! The file is polled instead of relying on filesystem notifications, which also picks up a file
+//! that is replaced by renaming, or by swapping a symlink as Kubernetes does for mounted configs.
+
+use std::{
+    fs,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+/// How often to check the file for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the file must stay unchanged before a change is reported, as editors often write a
+/// file in several steps.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+pub struct FileWatcher {
+    path: PathBuf,
+    last: Option<(SystemTime, u64)>,
+}
+
+impl FileWatcher {
+    pub fn new(path: PathBuf) -> FileWatcher {
+        let last = stamp(&path);
+        FileWatcher { path, last }
+    }
+
+    /// Complete once the file was modified since the last change reported.
+    pub async fn changed(&mut self) {
+        loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            let mut current = stamp(&self.path);
+            if current == self.last {
+                continue;
+            }
+
+            loop {
+                tokio::time::sleep(DEBOUNCE).await;
+                let settled = stamp(&self.path);
+                if settled == current {
+                    break;
+                }
+                current = settled;
+            }
+            self.last = current;
+            return;
+        }
+    }
+}
+
+/// Modification time and size of the file at `path`, or `None` while it does not exist.
+fn stamp(path: &PathBuf) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    /// Enough time for the watcher to poll and debounce a change.
+    const SETTLE: Duration = Duration::from_secs(3);
+
+    struct TestFile(PathBuf);
+
+    impl TestFile {
+        fn new(name: &str) -> TestFile {
+            let path = std::env::temp_dir().join(format!(
+                "mqtt2influxdb-{}-{}.yaml",
+                name,
+                std::process::id()
+            ));
+            fs::write(&path, "mappings: []\n").unwrap();
+            TestFile(path)
+        }
+    }
+
+    impl Drop for TestFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    async fn changes(watcher: &mut FileWatcher) -> bool {
+        tokio::time::timeout(SETTLE, watcher.changed())
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn unchanged_file() {
+        let file = TestFile::new("watch-unchanged");
+        let mut watcher = FileWatcher::new(file.0.clone());
+        assert!(!changes(&mut watcher).await);
+    }
+
+    #[tokio::test]
+    async fn size_change() {
+        let file = TestFile::new("watch-size");
+        let mut watcher = FileWatcher::new(file.0.clone());
+        fs::write(&file.0, "mappings: []\n# edited\n").unwrap();
+        assert!(changes(&mut watcher).await);
+        assert!(!changes(&mut watcher).await);
+    }
+
+    #[tokio::test]
+    async fn mtime_change() {
+        let file = TestFile::new("watch-mtime");
+        let mut watcher = FileWatcher::new(file.0.clone());
+        let modified = fs::metadata(&file.0).unwrap().modified().unwrap();
+        File::options()
+            .write(true)
+            .open(&file.0)
+            .unwrap()
+            .set_modified(modified + Duration::from_secs(60))
+            .unwrap();
+        assert!(changes(&mut watcher).await);
+    }
+
+    #[tokio::test]
+    async fn removed_and_recreated() {
+        let file = TestFile::new("watch-removed");
+        let mut watcher = FileWatcher::new(file.0.clone());
+        fs::remove_file(&file.0).unwrap();
+        assert!(changes(&mut watcher).await);
+        fs::write(&file.0, "mappings: []\n").unwrap();
+        assert!(changes(&mut watcher).await);
+    }
+}
@@ -0,0 +1,362 @@
+//! Gzip compression of request bodies.
+//!
+//! Only what is needed to compress line protocol well: a single deflate block with the fixed
+//! Huffman codes, and LZ77 matching on a hash of the next three bytes. Line protocol repeats
+//! measurement, tag and field names on every line, which these matches capture.
+
+/// Distance beyond which deflate cannot refer back.
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// Number of earlier positions with the same hash to try, bounding the time spent per byte.
+const MAX_CHAIN: usize = 32;
+const HASH_BITS: u32 = 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &b| {
+        CRC_TABLE[((crc ^ u32::from(b)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Writes bits from least to most significant, as deflate packs them.
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    len: u32,
+}
+
+impl BitWriter {
+    fn bits(&mut self, value: u32, len: u32) {
+        self.bits |= u64::from(value) << self.len;
+        self.len += len;
+        while self.len >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.len -= 8;
+        }
+    }
+
+    /// A Huffman code, which is packed starting at its most significant bit.
+    fn code(&mut self, code: u32, len: u32) {
+        self.bits(code.reverse_bits() >> (32 - len), len);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.len > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+
+    /// A literal byte, length code or end of block in the fixed literal/length code.
+    fn symbol(&mut self, symbol: u16) {
+        let symbol = u32::from(symbol);
+        match symbol {
+            0..=143 => self.code(0x30 + symbol, 8),
+            144..=255 => self.code(0x190 + symbol - 144, 9),
+            256..=279 => self.code(symbol - 256, 7),
+            _ => self.code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn length(&mut self, length: usize) {
+        let i = LENGTH_BASE.partition_point(|&base| usize::from(base) <= length) - 1;
+        self.symbol(257 + i as u16);
+        let extra = length - usize::from(LENGTH_BASE[i]);
+        self.bits(extra as u32, u32::from(LENGTH_EXTRA[i]));
+    }
+
+    fn distance(&mut self, distance: usize) {
+        let i = DISTANCE_BASE.partition_point(|&base| usize::from(base) <= distance) - 1;
+        self.code(i as u32, 5);
+        let extra = distance - usize::from(DISTANCE_BASE[i]);
+        self.bits(extra as u32, u32::from(DISTANCE_EXTRA[i]));
+    }
+}
+
+fn hash(data: &[u8]) -> usize {
+    let key = u32::from(data[0]) | u32::from(data[1]) << 8 | u32::from(data[2]) << 16;
+    (key.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+/// Make `pos` the most recent position with its hash.
+fn insert(data: &[u8], pos: usize, head: &mut [usize], prev: &mut [usize]) {
+    if pos + MIN_MATCH <= data.len() {
+        let h = hash(&data[pos..]);
+        prev[pos] = head[h];
+        head[h] = pos;
+    }
+}
+
+/// Compress `data` into a raw deflate stream.
+fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter {
+        out: Vec::with_capacity(data.len() / 2),
+        bits: 0,
+        len: 0,
+    };
+    // Final block, compressed with the fixed codes.
+    writer.bits(0b011, 3);
+
+    // Most recent position for every hash, and the position before it with the same hash.
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; data.len()];
+
+    let mut pos = 0;
+    while pos < data.len() {
+        let mut best = (0, 0);
+        if pos + MIN_MATCH <= data.len() {
+            let max = (data.len() - pos).min(MAX_MATCH);
+            let mut candidate = head[hash(&data[pos..])];
+            for _ in 0..MAX_CHAIN {
+                if candidate == usize::MAX || pos - candidate > WINDOW {
+                    break;
+                }
+                let len = data[candidate..]
+                    .iter()
+                    .zip(&data[pos..pos + max])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len > best.0 {
+                    best = (len, pos - candidate);
+                    if len == max {
+                        break;
+                    }
+                }
+                candidate = prev[candidate];
+            }
+        }
+
+        let (len, distance) = best;
+        if len >= MIN_MATCH {
+            writer.length(len);
+            writer.distance(distance);
+            for p in pos..pos + len {
+                insert(data, p, &mut head, &mut prev);
+            }
+            pos += len;
+        } else {
+            writer.symbol(u16::from(data[pos]));
+            insert(data, pos, &mut head, &mut prev);
+            pos += 1;
+        }
+    }
+
+    writer.symbol(256);
+    writer.finish()
+}
+
+/// Compress `data` into a gzip member.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    // Magic, deflate, no flags, no modification time, no extra flags, unknown OS.
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    out.extend(deflate(data));
+    out.extend(crc32(data).to_le_bytes());
+    out.extend((data.len() as u32).to_le_bytes());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads bits from least to most significant, like `BitWriter` writes them.
+    struct BitReader<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bits(&mut self, len: u32) -> u32 {
+            let mut value = 0;
+            for i in 0..len {
+                let bit = self.data[self.pos / 8] >> (self.pos % 8) & 1;
+                value |= u32::from(bit) << i;
+                self.pos += 1;
+            }
+            value
+        }
+
+        /// A Huffman code, which is read starting at its most significant bit.
+        fn code(&mut self, len: u32) -> u32 {
+            (0..len).fold(0, |code, _| code << 1 | self.bits(1))
+        }
+
+        fn symbol(&mut self) -> u16 {
+            let code = self.code(7);
+            if code <= 0x17 {
+                return 256 + code as u16;
+            }
+            let code = code << 1 | self.bits(1);
+            match code {
+                0x30..=0xbf => (code - 0x30) as u16,
+                0xc0..=0xc7 => (280 + code - 0xc0) as u16,
+                _ => (144 + (code << 1 | self.bits(1)) - 0x190) as u16,
+            }
+        }
+    }
+
+    /// Decompress a gzip member holding a single deflate block with the fixed codes, checking
+    /// its header and trailer.
+    fn decompress(gzip: &[u8]) -> Vec<u8> {
+        assert_eq!(gzip[..4], [0x1f, 0x8b, 8, 0]);
+        let (deflate, trailer) = gzip[10..].split_at(gzip.len() - 18);
+
+        let mut reader = BitReader {
+            data: deflate,
+            pos: 0,
+        };
+        assert_eq!(
+            reader.bits(3),
+            0b011,
+            "not a single final block with fixed codes"
+        );
+        let mut out: Vec<u8> = Vec::new();
+        loop {
+            let symbol = reader.symbol();
+            match symbol {
+                0..=255 => out.push(symbol as u8),
+                256 => break,
+                _ => {
+                    let i = usize::from(symbol - 257);
+                    let length = usize::from(LENGTH_BASE[i])
+                        + reader.bits(u32::from(LENGTH_EXTRA[i])) as usize;
+                    let i = reader.code(5) as usize;
+                    let distance = usize::from(DISTANCE_BASE[i])
+                        + reader.bits(u32::from(DISTANCE_EXTRA[i])) as usize;
+                    assert!(distance <= out.len().min(WINDOW));
+                    for _ in 0..length {
+                        out.push(out[out.len() - distance]);
+                    }
+                }
+            }
+        }
+        assert_eq!(
+            reader.pos.div_ceil(8),
+            deflate.len(),
+            "data after the final block"
+        );
+
+        assert_eq!(trailer[..4], crc32(&out).to_le_bytes());
+        assert_eq!(trailer[4..], (out.len() as u32).to_le_bytes());
+        out
+    }
+
+    /// Line protocol like that of a smart meter.
+    fn line_protocol(lines: usize) -> Vec<u8> {
+        (0..lines)
+            .map(|i| {
+                format!(
+                    "power,meter=E0031003000000000,phase=l{} delivered={}.{:03},returned=0.000 \
+                     {}\n",
+                    i % 3 + 1,
+                    i * 7 % 5000,
+                    i * 13 % 1000,
+                    1_700_000_000_000_000_000u64 + i as u64 * 1_000_000_000
+                )
+            })
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    /// Bytes without any repetition for the compressor to find.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn empty_input() {
+        let gzip = compress(b"");
+        assert_eq!(decompress(&gzip), b"");
+    }
+
+    #[test]
+    fn round_trip() {
+        for data in [
+            &b"a"[..],
+            b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+            b"m,site=home t=21.5\nm,site=home t=21.6\n",
+            &[0xff; MAX_MATCH * 3 + 1],
+        ] {
+            assert_eq!(decompress(&compress(data)), data);
+        }
+    }
+
+    #[test]
+    fn exactly_one_window() {
+        let data = line_protocol(1000)[..WINDOW].to_vec();
+        assert_eq!(decompress(&compress(&data)), data);
+
+        let data = noise(WINDOW);
+        assert_eq!(decompress(&compress(&data)), data);
+    }
+
+    #[test]
+    fn beyond_64_kib() {
+        let data = line_protocol(2000);
+        assert!(data.len() > 64 * 1024);
+        assert_eq!(decompress(&compress(&data)), data);
+
+        // Repeats of a block further back than the window must not be referred to.
+        let mut data = noise(WINDOW + 1);
+        data.extend_from_within(..);
+        data.extend(noise(64 * 1024));
+        assert_eq!(decompress(&compress(&data)), data);
+    }
+
+    #[test]
+    fn compresses_line_protocol() {
+        let data = line_protocol(2000);
+        // As claimed in the README.
+        assert!(compress(&data).len() * 6 < data.len());
+    }
+}
@@ -0,0 +1,92 @@
+use std::{
+    collections::BTreeSet,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+use crate::http;
+
+/// Connection state shared between the event loop and the health endpoint.
+#[derive(Debug)]
+pub struct Health {
+    mqtt_connected: AtomicBool,
+    /// Bit for every sink whose last ping or write failed, or that was not reached yet.
+    sinks_failing: AtomicU64,
+    /// Topic filters the server rejected a subscription to, such as for lack of permission, with
+    /// the index of their broker.
+    subscriptions_rejected: Mutex<BTreeSet<(usize, String)>>,
+}
+
+impl Health {
+    /// Health of a bridge writing to `sinks` sinks, none of which has been reached yet.
+    pub fn new(sinks: usize) -> Health {
+        assert!(sinks <= 64, "at most 64 sinks are supported");
+        Health {
+            mqtt_connected: AtomicBool::new(false),
+            sinks_failing: AtomicU64::new(u64::MAX.checked_shr(64 - sinks as u32).unwrap_or(0)),
+            subscriptions_rejected: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    pub fn set_mqtt_connected(&self, connected: bool) {
+        self.mqtt_connected.store(connected, Ordering::Relaxed);
+    }
+
+    /// Record whether the last ping or write to the sink at index `sink` succeeded.
+    pub fn set_sink_ok(&self, sink: usize, ok: bool) {
+        if ok {
+            self.sinks_failing
+                .fetch_and(!(1 << sink), Ordering::Relaxed);
+        } else {
+            self.sinks_failing.fetch_or(1 << sink, Ordering::Relaxed);
+        }
+    }
+
+    /// Record whether the server of broker `broker` accepted the subscription to `filter`.
+    pub fn set_subscription_ok(&self, broker: usize, filter: &str, ok: bool) {
+        let mut rejected = self.subscriptions_rejected.lock().unwrap();
+        if ok {
+            rejected.remove(&(broker, filter.to_owned()));
+        } else {
+            rejected.insert((broker, filter.to_owned()));
+        }
+    }
+
+    /// Forget the rejected subscriptions of broker `broker` whose filter is not `requested`
+    /// anymore.
+    pub fn retain_subscriptions(&self, broker: usize, requested: impl Fn(&str) -> bool) {
+        self.subscriptions_rejected
+            .lock()
+            .unwrap()
+            .retain(|(b, filter)| *b != broker || requested(filter));
+    }
+
+    /// Whether the bridge is connected to MQTT, all subscriptions were accepted and all sinks
+    /// are reachable.
+    pub fn is_ready(&self) -> bool {
+        self.mqtt_connected.load(Ordering::Relaxed)
+            && self.sinks_failing.load(Ordering::Relaxed) == 0
+            && self.subscriptions_rejected.lock().unwrap().is_empty()
+    }
+}
+
+fn respond(health: &Health, request: &Request<Body>) -> Response<Body> {
+    http::status(match (request.method(), request.uri().path()) {
+        (&Method::GET, "/healthz") => StatusCode::OK,
+        (&Method::GET, "/readyz") if health.is_ready() => StatusCode::OK,
+        (&Method::GET, "/readyz") => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::NOT_FOUND,
+    })
+}
+
+/// Bind to `addr` and serve `/healthz` and `/readyz` from a separate task.
+pub fn spawn(addr: SocketAddr, health: Arc<Health>) -> Result<(), hyper::Error> {
+    http::spawn(addr, move |request| respond(&health, request))?;
+    log::info!("Serving health checks on http://{}", addr);
+    Ok(())
+}
@@ -0,0 +1,27 @@
+//! Decoding of hexadecimal text, such as `01A3F0`, into bytes.
+
+fn nibble(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Decode pairs of hexadecimal digits in either case, ignoring surrounding whitespace.
+pub fn decode(text: &[u8]) -> Result<Vec<u8>, String> {
+    let text = text.trim_ascii();
+    if !text.len().is_multiple_of(2) {
+        return Err(format!("odd number of digits ({})", text.len()));
+    }
+    text.chunks(2)
+        .enumerate()
+        .map(|(i, pair)| {
+            let digit = |j: usize| {
+                nibble(pair[j]).ok_or_else(|| format!("invalid digit at position {}", 2 * i + j))
+            };
+            Ok(digit(0)? << 4 | digit(1)?)
+        })
+        .collect()
+}
@@ -0,0 +1,38 @@
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+
+/// Bind to `addr` and answer all requests using `handler` from a separate task.
+pub fn spawn<F>(addr: SocketAddr, handler: F) -> Result<(), hyper::Error>
+where
+    F: Fn(&Request<Body>) -> Response<Body> + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    let server = Server::try_bind(&addr)?.serve(make_service_fn(move |_| {
+        let handler = handler.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let response = handler(&request);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    }));
+
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            log::error!("HTTP server on {} failed: {}", addr, e);
+        }
+    });
+
+    Ok(())
+}
+
+/// An empty response with `status`, using its reason phrase as body.
+pub fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::from(status.canonical_reason().unwrap_or_default()));
+    *response.status_mut() = status;
+    response
+}
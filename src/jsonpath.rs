@@ -0,0 +1,352 @@
+//! The subset of JSONPath needed to pick values out of a payload.
+//!
+//! Supported are the root `$`, child names `.name` and `['name']`, array indices `[0]` and `[-1]`,
+//! wildcards `.*` and `[*]`, recursive descent `..name`, and filters such as `[?(@.id == 'a')]`
+//! comparing a relative path against a literal with `==`, `!=`, `<`, `<=`, `>` or `>=`, or only
+//! testing that it exists with `[?(@.id)]`.
+
+use std::cmp::Ordering;
+
+use serde_json::Value;
+
+#[derive(Debug)]
+enum Selector {
+    Name(String),
+    Index(i64),
+    Wildcard,
+    Filter(Filter),
+}
+
+#[derive(Debug)]
+enum Segment {
+    Child(Selector),
+    Descendant(Selector),
+}
+
+/// Comparison operator of a filter.
+#[derive(Debug, Clone, Copy)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Op {
+    /// Split an operator off the start of `s`.
+    pub fn strip_prefix(s: &str) -> Option<(Op, &str)> {
+        [
+            ("==", Op::Eq),
+            ("!=", Op::Ne),
+            ("<=", Op::Le),
+            (">=", Op::Ge),
+            ("<", Op::Lt),
+            (">", Op::Gt),
+        ]
+        .into_iter()
+        .find_map(|(token, op)| Some((op, s.strip_prefix(token)?)))
+    }
+
+    /// Compare `value` against `literal`. Numbers and strings are ordered, other values can only
+    /// be equal or not.
+    pub fn compare(self, value: &Value, literal: &Value) -> bool {
+        let ordering = match (value, literal) {
+            (Value::Number(a), Value::Number(b)) => a.as_f64().partial_cmp(&b.as_f64()),
+            (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+            (a, b) if a == b => Some(Ordering::Equal),
+            _ => None,
+        };
+
+        match self {
+            Op::Eq => ordering == Some(Ordering::Equal),
+            Op::Ne => ordering != Some(Ordering::Equal),
+            Op::Lt => ordering == Some(Ordering::Less),
+            Op::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+            Op::Gt => ordering == Some(Ordering::Greater),
+            Op::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Filter {
+    /// Path relative to the candidate `@`, only made up of names and indices.
+    path: Vec<Selector>,
+    /// Comparison against a literal, or `None` to only test that `path` exists.
+    comparison: Option<(Op, Value)>,
+}
+
+/// A parsed JSONPath expression.
+#[derive(Debug)]
+pub struct JsonPath(Vec<Segment>);
+
+struct Parser<'s> {
+    src: &'s str,
+    pos: usize,
+}
+
+impl<'s> Parser<'s> {
+    fn rest(&self) -> &'s str {
+        &self.src[self.pos..]
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.pos = self.src.len() - self.rest().trim_start().len();
+    }
+
+    fn error(&self, expected: &str) -> String {
+        format!(
+            "expected {} at position {} of {:?}",
+            expected, self.pos, self.src
+        )
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), String> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("{:?}", token)))
+        }
+    }
+
+    /// An unquoted name, as in `.name`.
+    fn name(&mut self) -> Result<String, String> {
+        let len = self
+            .rest()
+            .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-'))
+            .unwrap_or(self.rest().len());
+        if len == 0 {
+            return Err(self.error("a name"));
+        }
+        let name = self.rest()[..len].to_owned();
+        self.pos += len;
+        Ok(name)
+    }
+
+    /// A string quoted with `'` or `"`, where `\` escapes the next character.
+    fn quoted(&mut self) -> Result<String, String> {
+        let quote = match self.rest().chars().next() {
+            Some(c @ ('\'' | '"')) => c,
+            _ => return Err(self.error("a quoted string")),
+        };
+        self.pos += 1;
+
+        let mut s = String::new();
+        let mut chars = self.rest().char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some((_, c)) => s.push(c),
+                    None => break,
+                },
+                c if c == quote => {
+                    self.pos += i + 1;
+                    return Ok(s);
+                }
+                c => s.push(c),
+            }
+        }
+        self.pos = self.src.len();
+        Err(self.error("a closing quote"))
+    }
+
+    fn index(&mut self) -> Result<i64, String> {
+        let len = self
+            .rest()
+            .char_indices()
+            .find(|&(i, c)| !(c.is_ascii_digit() || (i == 0 && c == '-')))
+            .map_or(self.rest().len(), |(i, _)| i);
+        let index = self.rest()[..len]
+            .parse()
+            .map_err(|_| self.error("an index"))?;
+        self.pos += len;
+        Ok(index)
+    }
+
+    /// The selector after a `.`, or after `..`.
+    fn dotted(&mut self) -> Result<Selector, String> {
+        if self.eat("*") {
+            Ok(Selector::Wildcard)
+        } else if self.rest().starts_with('[') {
+            self.bracketed()
+        } else {
+            self.name().map(Selector::Name)
+        }
+    }
+
+    /// A selector between `[` and `]`.
+    fn bracketed(&mut self) -> Result<Selector, String> {
+        self.expect("[")?;
+        self.skip_whitespace();
+        let selector = if self.eat("*") {
+            Selector::Wildcard
+        } else if self.eat("?(") {
+            let filter = self.filter()?;
+            self.expect(")")?;
+            Selector::Filter(filter)
+        } else if self.rest().starts_with(['\'', '"']) {
+            Selector::Name(self.quoted()?)
+        } else {
+            Selector::Index(self.index()?)
+        };
+        self.skip_whitespace();
+        self.expect("]")?;
+        Ok(selector)
+    }
+
+    fn filter(&mut self) -> Result<Filter, String> {
+        self.skip_whitespace();
+        self.expect("@")?;
+
+        let mut path = Vec::new();
+        loop {
+            if self.eat(".") {
+                path.push(Selector::Name(self.name()?));
+            } else if self.rest().starts_with('[') {
+                match self.bracketed()? {
+                    selector @ (Selector::Name(_) | Selector::Index(_)) => path.push(selector),
+                    _ => return Err(self.error("a name or index in the filter path")),
+                }
+            } else {
+                break;
+            }
+        }
+
+        self.skip_whitespace();
+        let op = Op::strip_prefix(self.rest()).map(|(op, rest)| {
+            self.pos = self.src.len() - rest.len();
+            op
+        });
+
+        let comparison = match op {
+            Some(op) => {
+                self.skip_whitespace();
+                Some((op, self.literal()?))
+            }
+            None => None,
+        };
+        self.skip_whitespace();
+
+        Ok(Filter { path, comparison })
+    }
+
+    fn literal(&mut self) -> Result<Value, String> {
+        if self.rest().starts_with(['\'', '"']) {
+            return self.quoted().map(Value::String);
+        }
+
+        let len = self
+            .rest()
+            .find(|c: char| c.is_whitespace() || c == ')')
+            .unwrap_or(self.rest().len());
+        let literal = serde_json::from_str(&self.rest()[..len])
+            .map_err(|_| self.error("a number, string, true, false or null"))?;
+        self.pos += len;
+        Ok(literal)
+    }
+}
+
+impl JsonPath {
+    pub fn parse(src: &str) -> Result<JsonPath, String> {
+        let mut parser = Parser { src, pos: 0 };
+        parser.expect("$")?;
+
+        let mut segments = Vec::new();
+        while !parser.rest().is_empty() {
+            if parser.eat("..") {
+                segments.push(Segment::Descendant(parser.dotted()?));
+            } else if parser.eat(".") {
+                segments.push(Segment::Child(parser.dotted()?));
+            } else if parser.rest().starts_with('[') {
+                segments.push(Segment::Child(parser.bracketed()?));
+            } else {
+                return Err(parser.error("\".\" or \"[\""));
+            }
+        }
+
+        Ok(JsonPath(segments))
+    }
+
+    /// All values matched by the path, in document order.
+    pub fn query<'v>(&self, root: &'v Value) -> Vec<&'v Value> {
+        let mut nodes = vec![root];
+        for segment in &self.0 {
+            let mut next = Vec::new();
+            for node in nodes {
+                match segment {
+                    Segment::Child(selector) => selector.select(node, &mut next),
+                    Segment::Descendant(selector) => descend(node, selector, &mut next),
+                }
+            }
+            nodes = next;
+        }
+        nodes
+    }
+}
+
+fn children(value: &Value) -> Box<dyn Iterator<Item = &Value> + '_> {
+    match value {
+        Value::Array(a) => Box::new(a.iter()),
+        Value::Object(o) => Box::new(o.values()),
+        _ => Box::new(std::iter::empty()),
+    }
+}
+
+fn descend<'v>(value: &'v Value, selector: &Selector, out: &mut Vec<&'v Value>) {
+    selector.select(value, out);
+    for child in children(value) {
+        descend(child, selector, out);
+    }
+}
+
+impl Selector {
+    fn get<'v>(&self, value: &'v Value) -> Option<&'v Value> {
+        match (self, value) {
+            (Selector::Name(name), Value::Object(o)) => o.get(name),
+            (Selector::Index(i), Value::Array(a)) => {
+                let i = if *i < 0 { a.len() as i64 + i } else { *i };
+                a.get(usize::try_from(i).ok()?)
+            }
+            _ => None,
+        }
+    }
+
+    fn select<'v>(&self, value: &'v Value, out: &mut Vec<&'v Value>) {
+        match self {
+            Selector::Name(_) | Selector::Index(_) => out.extend(self.get(value)),
+            Selector::Wildcard => out.extend(children(value)),
+            Selector::Filter(filter) => {
+                out.extend(children(value).filter(|child| filter.matches(child)))
+            }
+        }
+    }
+}
+
+impl Filter {
+    fn matches(&self, candidate: &Value) -> bool {
+        let Some(value) = self
+            .path
+            .iter()
+            .try_fold(candidate, |value, selector| selector.get(value))
+        else {
+            return false;
+        };
+
+        let Some((op, literal)) = &self.comparison else {
+            return true;
+        };
+
+        op.compare(value, literal)
+    }
+}
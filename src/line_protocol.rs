@@ -0,0 +1,247 @@
+use std::{collections::HashMap, fmt::Write};
+
+use clap::ValueEnum;
+
+use crate::point::{Point, Value};
+
+/// What to do with measurement, tag and field names containing characters that have a special
+/// meaning in line protocol: spaces, commas, equals signs, quotes and backslashes. Tag values
+/// containing newlines or other control characters are treated the same way.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum NamePolicy {
+    /// Keep the name and escape these characters when writing.
+    Escape,
+    /// Replace these characters with `_`.
+    Sanitize,
+    /// Drop points with such names.
+    Reject,
+}
+
+/// Characters that need escaping in some part of line protocol.
+fn is_special(c: char) -> bool {
+    matches!(c, ' ' | ',' | '=' | '"' | '\\')
+}
+
+impl NamePolicy {
+    fn normalize(self, name: &str) -> Result<String, String> {
+        // Control characters such as newlines are not escaped in names, so are always replaced.
+        let replace = |c: char| match self {
+            _ if c.is_control() => '_',
+            NamePolicy::Sanitize if is_special(c) => '_',
+            _ => c,
+        };
+        match self {
+            NamePolicy::Reject if name.chars().any(|c| c.is_control() || is_special(c)) => {
+                Err(format!("invalid name {:?}", name))
+            }
+            _ => Ok(name.chars().map(replace).collect()),
+        }
+    }
+
+    fn normalize_keys<'a>(
+        self,
+        map: HashMap<String, Value<'a>>,
+    ) -> Result<HashMap<String, Value<'a>>, String> {
+        map.into_iter()
+            .map(|(key, value)| Ok((self.normalize(&key)?, value)))
+            .collect()
+    }
+
+    /// Apply the policy to the control characters in a tag value, which are escaped otherwise.
+    fn normalize_tag_value<'a>(self, key: &str, value: Value<'a>) -> Result<Value<'a>, String> {
+        match (self, value) {
+            (NamePolicy::Sanitize, Value::String(s)) if s.contains(char::is_control) => {
+                Ok(Value::String(s.replace(char::is_control, "_").into()))
+            }
+            (NamePolicy::Reject, Value::String(s)) if s.contains(char::is_control) => {
+                Err(format!("invalid value {:?} of tag {}", s, key))
+            }
+            (_, value) => Ok(value),
+        }
+    }
+
+    /// Apply the policy to the measurement, tag and field names and tag values of `point`, or
+    /// describe the first name or value that is rejected.
+    pub fn apply(self, point: Point<'_>) -> Result<Point<'_>, String> {
+        let tags = point
+            .tags
+            .into_iter()
+            .map(|(key, value)| {
+                let value = self.normalize_tag_value(&key, value)?;
+                Ok((key, value))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Point {
+            measurement: self.normalize(&point.measurement)?,
+            tags: self.normalize_keys(tags)?,
+            fields: self.normalize_keys(point.fields)?,
+            timestamp: point.timestamp,
+        })
+    }
+}
+
+fn escape(value: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            // These would end the line or the value, so are written as escape sequences, like
+            // Telegraf does.
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c => {
+                if special.contains(&c) {
+                    escaped.push('\\');
+                }
+                escaped.push(c);
+            }
+        }
+    }
+    escaped
+}
+
+fn escape_measurement(value: &str) -> String {
+    escape(value, &[',', ' ', '\\'])
+}
+
+fn escape_key(value: &str) -> String {
+    escape(value, &[',', '=', ' ', '\\'])
+}
+
+fn push_value(line: &mut String, value: &Value, is_field: bool) {
+    let _ = match value {
+        Value::String(s) if is_field => write!(line, "\"{}\"", escape(s, &['"', '\\'])),
+        Value::String(s) => write!(line, "{}", escape_key(s)),
+        Value::Integer(i) if is_field => write!(line, "{}i", i),
+        Value::Integer(i) => write!(line, "{}", i),
+        Value::UnsignedInteger(u) if is_field => write!(line, "{}u", u),
+        Value::UnsignedInteger(u) => write!(line, "{}", u),
+        Value::Float(f) => write!(line, "{}", f),
+        Value::Boolean(b) => write!(line, "{}", b),
+    };
+}
+
+/// Append `point` to `lines` as a single line of InfluxDB line protocol, including the newline.
+///
+/// Tags and fields are sorted by key, as recommended by InfluxDB. Tags with an empty value,
+/// which line protocol cannot express, are left out.
+pub fn push_point(lines: &mut String, point: &Point) {
+    lines.push_str(&escape_measurement(&point.measurement));
+
+    let mut tags: Vec<_> = point
+        .tags
+        .iter()
+        .filter(|(_, value)| !matches!(value, Value::String(s) if s.is_empty()))
+        .collect();
+    tags.sort_by(|a, b| a.0.cmp(b.0));
+    for (key, value) in tags {
+        lines.push(',');
+        lines.push_str(&escape_key(key));
+        lines.push('=');
+        push_value(lines, value, false);
+    }
+
+    let mut fields: Vec<_> = point.fields.iter().collect();
+    fields.sort_by(|a, b| a.0.cmp(b.0));
+    for (i, (key, value)) in fields.into_iter().enumerate() {
+        lines.push(if i == 0 { ' ' } else { ',' });
+        lines.push_str(&escape_key(key));
+        lines.push('=');
+        push_value(lines, value, true);
+    }
+
+    if let Some(timestamp) = point.timestamp {
+        let _ = write!(lines, " {}", timestamp);
+    }
+
+    lines.push('\n');
+}
+
+/// Serialize `points` to InfluxDB line protocol, one point per line.
+pub fn serialize<'p>(points: impl IntoIterator<Item = &'p Point<'p>>) -> String {
+    let mut lines = String::new();
+    for point in points {
+        push_point(&mut lines, point);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Value<'static> {
+        Value::String(s.to_owned().into())
+    }
+
+    fn point() -> Point<'static> {
+        Point::new("living room")
+            .add_tag("sensor,id", text("a=1"))
+            .add_field("temp °C", Value::Float(21.5))
+            .add_field("note", text("say \"hi\"\\"))
+    }
+
+    #[test]
+    fn names_are_escaped() {
+        let point = NamePolicy::Escape.apply(point()).unwrap();
+        assert_eq!(
+            serialize([&point]),
+            "living\\ room,sensor\\,id=a\\=1 note=\"say \\\"hi\\\"\\\\\",temp\\ °C=21.5\n"
+        );
+    }
+
+    #[test]
+    fn names_are_sanitized() {
+        let point = NamePolicy::Sanitize.apply(point()).unwrap();
+        assert_eq!(point.measurement, "living_room");
+        assert!(point.tags.contains_key("sensor_id"));
+        assert!(point.fields.contains_key("temp_°C"));
+        assert_eq!(
+            serialize([&point]),
+            "living_room,sensor_id=a\\=1 note=\"say \\\"hi\\\"\\\\\",temp_°C=21.5\n"
+        );
+    }
+
+    #[test]
+    fn names_are_rejected() {
+        assert!(NamePolicy::Reject.apply(point()).is_err());
+        let point = Point::new("température").add_field("°C", Value::Float(21.5));
+        assert!(NamePolicy::Reject.apply(point).is_ok());
+    }
+
+    #[test]
+    fn newlines_in_values_do_not_split_the_line() {
+        let point = Point::new("m")
+            .add_tag("room", text("living\nroom"))
+            .add_field("note", text("line 1\r\nline 2\t"));
+        assert_eq!(
+            serialize([&NamePolicy::Escape.apply(point.clone()).unwrap()]),
+            "m,room=living\\nroom note=\"line 1\\r\\nline 2\\t\"\n"
+        );
+        let sanitized = NamePolicy::Sanitize.apply(point.clone()).unwrap();
+        assert_eq!(sanitized.tags["room"], text("living_room"));
+        assert!(NamePolicy::Reject.apply(point).is_err());
+    }
+
+    #[test]
+    fn empty_tags_are_left_out() {
+        let point = Point::new("m")
+            .add_tag("room", text(""))
+            .add_tag("site", text("home"))
+            .add_field("v", Value::Integer(1));
+        assert_eq!(serialize([&point]), "m,site=home v=1i\n");
+    }
+
+    #[test]
+    fn unsigned_integers() {
+        let point = Point::new("counters")
+            .add_tag("max", Value::UnsignedInteger(u64::MAX))
+            .add_field("count", Value::UnsignedInteger(u64::MAX))
+            .add_field("signed", Value::Integer(i64::MIN));
+        assert_eq!(
+            serialize([&point]),
+            "counters,max=18446744073709551615 count=18446744073709551615u,\
+             signed=-9223372036854775808i\n"
+        );
+    }
+}
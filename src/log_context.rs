@@ -0,0 +1,65 @@
+//! Fields that are added to JSON log lines, such as the topic of the message being handled, so
+//! log aggregators can filter on them without parsing the message.
+
+use std::{cell::RefCell, marker::PhantomData};
+
+thread_local! {
+    static FIELDS: RefCell<Vec<(&'static str, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Keeps a field set until it is dropped.
+///
+/// Fields are kept per thread, so the guard must not be held across an `.await`, after which the
+/// task may continue on another thread. It is not `Send` to catch most such uses.
+#[must_use]
+pub struct Guard(PhantomData<*const ()>);
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        FIELDS.with(|fields| fields.borrow_mut().pop());
+    }
+}
+
+/// Add `key` to everything logged on this thread until the guard is dropped.
+pub fn set(key: &'static str, value: impl ToString) -> Guard {
+    FIELDS.with(|fields| fields.borrow_mut().push((key, value.to_string())));
+    Guard(PhantomData)
+}
+
+/// Call `f` with every field currently set, in the order they were set.
+pub fn for_each(mut f: impl FnMut(&'static str, &str)) {
+    FIELDS.with(|fields| {
+        for (key, value) in fields.borrow().iter() {
+            f(key, value);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> Vec<(&'static str, String)> {
+        let mut fields = Vec::new();
+        for_each(|key, value| fields.push((key, value.to_owned())));
+        fields
+    }
+
+    #[test]
+    fn nested_fields() {
+        let topic = set("topic", "sensors/kitchen");
+        {
+            let _measurement = set("measurement", "temperature");
+            assert_eq!(
+                fields(),
+                [
+                    ("topic", "sensors/kitchen".to_owned()),
+                    ("measurement", "temperature".to_owned())
+                ]
+            );
+        }
+        assert_eq!(fields(), [("topic", "sensors/kitchen".to_owned())]);
+        drop(topic);
+        assert!(fields().is_empty());
+    }
+}
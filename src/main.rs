@@ -1,145 +1,1781 @@
+mod cardinality;
 pub mod config;
+mod dedupe;
+mod file_watch;
+mod gzip;
+mod health;
+mod hex;
+mod http;
+mod jsonpath;
+mod line_protocol;
+mod log_context;
+mod metrics;
+mod mqtt;
+mod msgpack;
+mod point;
+mod signal;
+mod sink;
+mod spool;
+mod tls;
+mod writer;
 
-use std::{fs::File, path::PathBuf, time::{Duration, Instant}, collections::{BTreeMap, btree_map::Entry}};
+use std::{
+    collections::{btree_map::Entry, BTreeMap, HashMap, VecDeque},
+    io::Write,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use clap::Parser;
-use config::Configuration;
-use influxdb_rs::{Point, Precision};
-use rumqttc::{
-    matches, AsyncClient, ConnAck, ConnectReturnCode, MqttOptions, Packet, Publish, QoS,
+use cardinality::{CardinalityAction, CardinalityGuard};
+use clap::{
+    builder::PossibleValuesParser, builder::TypedValueParser, Args, Parser, Subcommand, ValueEnum,
 };
+use config::{Broker, Configuration, ExtractError};
+use dedupe::Deduplicator;
+use health::Health;
+use influxdb_rs::Precision;
+use line_protocol::NamePolicy;
+use metrics::{DropReason, Metrics};
+use mqtt::{Message, Subscription};
+use point::{Point, Value};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use rumqttc::{matches, Transport};
+use sink::{PointSink, WriteTarget};
+use spool::Spool;
+use tokio::sync::{mpsc, watch};
 use url::Url;
+use writer::{QueuedPoint, Queues, Writer};
 
 #[derive(Parser)]
 #[clap(version, about)]
-struct Args {
+struct Cli {
+    /// Format to write log lines in.
+    #[clap(env, long, global = true, value_enum, default_value = "text")]
+    log_format: LogFormat,
+
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum InfluxdbVersion {
+    /// InfluxDB 1.x, writing to a database with optional username and password.
+    V1,
+    /// InfluxDB 2.x, writing to a bucket of an organization with a token.
+    V2,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SinkKind {
+    /// The InfluxDB server given with the `--influxdb-*` options.
+    Influxdb,
+    /// Line protocol appended to `--sink-path`, as accepted by `influx write`.
+    File,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    /// Human readable lines, as formatted by `env_logger`.
+    Text,
+    /// One JSON object per line, with `timestamp`, `level`, `target` and `message`, and `topic`
+    /// and `measurement` when logged while handling a message.
+    Json,
+}
+
+/// Set up logging to stderr, filtered with `RUST_LOG`.
+fn init_logging(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if let LogFormat::Json = format {
+        builder.format(|buf, record| {
+            let mut line = serde_json::json!({
+                "timestamp": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            log_context::for_each(|key, value| line[key] = value.into());
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Forward MQTT messages to InfluxDB2.
+    Run(Box<RunArgs>),
+    /// Check the mapping configuration file without connecting to MQTT or InfluxDB2.
+    Validate(ValidateArgs),
+}
+
+#[derive(Args)]
+struct ValidateArgs {
+    /// Path to the mapping configuration file to check, `-` for standard input, or an `http://`
+    /// or `https://` URL to fetch it from.
+    #[clap(env, long)]
+    config: PathBuf,
+
+    /// Also exit with a non-zero status if there are warnings, such as in CI.
+    #[clap(long)]
+    strict: bool,
+}
+
+#[derive(Args)]
+struct RunArgs {
     /// Url for the MQTT server to connect to.
     #[clap(env, long, default_value = "mqtt://localhost")]
     mqtt_url: Url,
 
+    /// MQTT protocol version to connect with.
+    #[clap(env, long, value_enum, default_value = "v3")]
+    mqtt_version: mqtt::Version,
+
     /// Client ID used by this application to identify itself to the MQTT server.
     #[clap(env, long, default_value = "mqtt2influxdb")]
     mqtt_client_id: String,
 
+    /// Username used to authenticate with the MQTT server.
+    #[clap(env, long)]
+    mqtt_username: Option<String>,
+
+    /// Password used to authenticate with the MQTT server. Requires `--mqtt-username`.
+    #[clap(env, long, requires = "mqtt_username")]
+    mqtt_password: Option<String>,
+
+    /// Start every connection with a clean MQTT session. With `false`, the server keeps the
+    /// session of `--mqtt-client-id` while the bridge is offline and queues the messages it
+    /// missed. Only messages on entries with `qos` 1 or 2 are queued; QoS 0 messages are lost.
+    #[clap(env, long, default_value_t = true, action = clap::ArgAction::Set)]
+    mqtt_clean_session: bool,
+
+    /// Seconds between keep-alive pings to the MQTT server while otherwise idle. Longer intervals
+    /// save traffic on metered links, but take longer to detect a lost connection. Must be
+    /// within the maximum some servers enforce.
+    #[clap(env, long, default_value_t = 5, value_parser = clap::value_parser!(u64).range(5..=65535))]
+    mqtt_keep_alive: u64,
+
+    /// Number of requests to the MQTT client, and of received messages, that can be queued
+    /// before the bridge waits for them to be handled. Raising it helps with bursts such as the
+    /// retained messages sent when subscribing, at the cost of memory for up to this many
+    /// messages per broker.
+    #[clap(env, long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    mqtt_channel_capacity: u64,
+
+    /// Upper bound for the delay between attempts to reconnect to an MQTT server, which starts
+    /// at one second and doubles on every failed attempt.
+    #[clap(env, long, default_value = "30s", value_parser = humantime::parse_duration)]
+    mqtt_reconnect_max_delay: Duration,
+
+    /// Exit with a failure after this many consecutive failed attempts to connect to an MQTT
+    /// server, so an orchestrator can restart the bridge or report it, instead of retrying
+    /// forever. Points received until then are still written.
+    #[clap(env, long, value_parser = clap::value_parser!(u32).range(1..))]
+    mqtt_reconnect_max_attempts: Option<u32>,
+
+    /// Subscribe only to the most general filters, leaving out those they already cover, such as
+    /// `sensors/+/temp` when another entry subscribes to `sensors/#`. This saves SUBSCRIBE packets
+    /// with many entries. Messages are still routed to entries by their own `src_topic`, and the
+    /// combined subscription uses the highest QoS of the entries it covers.
+    #[clap(env, long)]
+    mqtt_consolidate_subscriptions: bool,
+
+    /// Topic the MQTT server publishes `--mqtt-will-payload` to when the bridge disconnects
+    /// ungracefully. No last will is configured without it.
+    #[clap(env, long)]
+    mqtt_will_topic: Option<String>,
+
+    /// Payload of the last will message.
+    #[clap(env, long, default_value = "", requires = "mqtt_will_topic")]
+    mqtt_will_payload: String,
+
+    /// QoS level of the last will message.
+    #[clap(env, long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=2), requires = "mqtt_will_topic")]
+    mqtt_will_qos: u8,
+
+    /// PEM file with the CA certificate(s) to trust for `mqtts://` connections. Defaults to the
+    /// system root store.
+    #[clap(env, long)]
+    mqtt_ca_cert: Option<PathBuf>,
+
+    /// PEM file with the client certificate for mutual TLS. Requires `--mqtt-client-key`.
+    #[clap(env, long, requires = "mqtt_client_key")]
+    mqtt_client_cert: Option<PathBuf>,
+
+    /// PEM file with the private key belonging to `--mqtt-client-cert`.
+    #[clap(env, long, requires = "mqtt_client_cert")]
+    mqtt_client_key: Option<PathBuf>,
+
     /// Url for the InfluxDB2 server to connect to.
     #[clap(env, long, default_value = "http://localhost:8086")]
     influxdb_url: Url,
 
-    /// InfluxDB2 bucket to write all the data to.
+    /// PEM file with the certificate of a CA to trust for an `https://` InfluxDB URL, in addition
+    /// to the system roots, for servers with a certificate of an internal CA or a self-signed
+    /// one.
     #[clap(env, long)]
-    influxdb_bucket: String,
+    influxdb_ca_cert: Option<PathBuf>,
+
+    /// Do not verify the certificate of an `https://` InfluxDB URL at all. Only meant for
+    /// testing, as anyone in between can then read and alter the points and token.
+    #[clap(env, long, conflicts_with = "influxdb_ca_cert")]
+    influxdb_insecure: bool,
 
-    /// InfluxDB2 organization for the database.
+    /// Major version of the InfluxDB server, which determines the API used to write.
+    #[clap(env, long, value_enum, default_value = "v2")]
+    influxdb_version: InfluxdbVersion,
+
+    /// InfluxDB2 bucket to write all the data to. With InfluxDB 1, the database, optionally
+    /// followed by `/` and a retention policy. Required with `--sink influxdb`.
     #[clap(env, long)]
-    influxdb_org: String, // (why do we need to send this?)
+    influxdb_bucket: Option<String>,
 
-    /// InfluxDB2 secret token for the account to use.
+    /// InfluxDB2 organization that `--influxdb-bucket` belongs to, as buckets are only unique
+    /// within an organization. Required with InfluxDB 2.
     #[clap(env, long)]
-    influxdb_jwt: String,
+    influxdb_org: Option<String>,
 
-    /// Path to the mapping configuration file used to translate MQTT messages to InfluxDB2 points.
+    /// InfluxDB2 secret token for the account to use. Required with InfluxDB 2, unless
+    /// `--influxdb-jwt-file` is given.
+    #[clap(env, long)]
+    influxdb_jwt: Option<String>,
+
+    /// File to read the InfluxDB2 secret token from, instead of passing it with `--influxdb-jwt`.
+    #[clap(env, long, conflicts_with = "influxdb_jwt")]
+    influxdb_jwt_file: Option<PathBuf>,
+
+    /// Username to authenticate with InfluxDB 1.
+    #[clap(env, long, requires = "influxdb_password")]
+    influxdb_username: Option<String>,
+
+    /// Password to authenticate with InfluxDB 1.
+    #[clap(env, long, requires = "influxdb_username")]
+    influxdb_password: Option<String>,
+
+    /// Timestamp precision used when writing points to InfluxDB.
+    #[clap(
+        env,
+        long,
+        default_value = "ms",
+        value_parser = PossibleValuesParser::new(["s", "ms", "us", "ns"]).map(|s| parse_precision(&s))
+    )]
+    influxdb_precision: Precision,
+
+    /// Compress write requests to InfluxDB with gzip, which typically shrinks line protocol to
+    /// a sixth of its size.
+    #[clap(env, long)]
+    influxdb_gzip: bool,
+
+    /// Number of buffered points at which they are written to InfluxDB in a single request.
+    #[clap(env, long, default_value_t = 100)]
+    influxdb_batch_size: usize,
+
+    /// Maximum time a point is buffered before being written to InfluxDB, e.g. `1s` or `500ms`.
+    #[clap(env, long, default_value = "1s", value_parser = humantime::parse_duration)]
+    influxdb_flush_interval: Duration,
+
+    /// Address to serve the `/healthz` and `/readyz` endpoints on, e.g. `0.0.0.0:8080`.
+    /// `/readyz` reports not ready while a broker is disconnected, a subscription was rejected or
+    /// a sink is unreachable.
+    #[clap(env, long)]
+    health_addr: Option<SocketAddr>,
+
+    /// Address to serve Prometheus metrics on at `/metrics`, e.g. `0.0.0.0:9100`.
+    #[clap(env, long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Number of times a failed write to InfluxDB is retried before its points are dropped.
+    #[clap(env, long, default_value_t = 3)]
+    influxdb_max_retries: u32,
+
+    /// Delay before retrying a failed write to InfluxDB, doubling for every further retry.
+    #[clap(env, long, default_value = "1s", value_parser = humantime::parse_duration)]
+    influxdb_retry_delay: Duration,
+
+    /// Wait up to this long at startup for InfluxDB to respond to a ping, e.g. `2m`, and exit if
+    /// it does not. Without it, the bridge starts right away and writes fail until InfluxDB is up.
+    #[clap(env, long, value_parser = humantime::parse_duration)]
+    influxdb_startup_timeout: Option<Duration>,
+
+    /// Directory to keep points in while InfluxDB is unreachable, replaying them once it is back.
+    /// Without it, points are dropped when all retries fail. Only used for `--sink influxdb`.
+    #[clap(env, long)]
+    spool_dir: Option<PathBuf>,
+
+    /// Maximum size of the spool in bytes, beyond which the oldest points are dropped.
+    #[clap(env, long, default_value_t = 64 * 1024 * 1024)]
+    spool_max_bytes: u64,
+
+    /// Maximum size of a message payload in bytes. Larger messages are dropped before they are
+    /// parsed.
+    #[clap(env, long, default_value_t = 1024 * 1024)]
+    max_payload_bytes: usize,
+
+    /// What to do with measurement, tag and field names containing spaces, commas, equals signs,
+    /// quotes or backslashes, and with tag values containing newlines.
+    #[clap(env, long, value_enum, default_value = "escape")]
+    name_policy: NamePolicy,
+
+    /// Number of distinct values of a tag beyond which `--tag-cardinality-action` applies to new
+    /// ones, to catch timestamps or IDs that end up in tags by mistake. Every distinct value
+    /// creates another series in InfluxDB. Tracked for each tag name across all measurements.
+    #[clap(env, long)]
+    max_tag_values: Option<usize>,
+
+    /// What to do with a new tag value once `--max-tag-values` is reached for its tag.
+    #[clap(
+        env,
+        long,
+        value_enum,
+        default_value = "warn",
+        requires = "max_tag_values"
+    )]
+    tag_cardinality_action: CardinalityAction,
+
+    /// Interval at which to log how many messages were dropped and why, e.g. `1m`.
+    #[clap(env, long, default_value = "1m", value_parser = humantime::parse_duration)]
+    drop_log_interval: Duration,
+
+    /// Interval at which to log the rate of points received, the batches written and their
+    /// latency, e.g. `1m`, to tell whether writing keeps up with MQTT.
+    #[clap(env, long, value_parser = humantime::parse_duration)]
+    stats_interval: Option<Duration>,
+
+    /// Interval at which to write a heartbeat point, e.g. `1m`, whether messages are received or
+    /// not, so dashboards and alerts can tell that the bridge is alive.
+    #[clap(env, long, value_parser = humantime::parse_duration)]
+    heartbeat_interval: Option<Duration>,
+
+    /// Measurement of the heartbeat points, with a `count` field that increments with every
+    /// heartbeat and an `uptime` field in seconds.
+    #[clap(env, long, default_value = "mqtt2influxdb_heartbeat")]
+    heartbeat_measurement: String,
+
+    /// Also subscribe to `#` and log the topics of messages that match no entry, to discover
+    /// what is published. Each topic is logged at most once a minute.
+    #[clap(env, long)]
+    log_unmatched: bool,
+
+    /// Additional MQTT topic filter to subscribe to on every broker, only to log the payloads
+    /// of its messages without writing them, at most once every 10 seconds per topic. Given
+    /// more than once or as a comma-separated list for several filters.
+    #[clap(env, long, value_delimiter = ',')]
+    debug_subscribe: Vec<String>,
+
+    /// Reload the configuration file whenever it changes, as on SIGHUP. Not supported for
+    /// configurations read from standard input or a URL.
+    #[clap(env, long)]
+    watch_config: bool,
+
+    /// Where to write points to. Given more than once or as a comma-separated list, every point
+    /// is written to each of the sinks.
+    #[clap(
+        env,
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "influxdb"
+    )]
+    sink: Vec<SinkKind>,
+
+    /// File to append line protocol to with `--sink file`, or `-` for standard output.
+    #[clap(env, long)]
+    sink_path: Option<PathBuf>,
+
+    /// Log the points that would be written instead of connecting to InfluxDB.
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Print the topic filters that would be subscribed to on every broker, with the
+    /// measurements of the entries they serve, and exit without connecting.
+    #[clap(long)]
+    config_check: bool,
+
+    /// Exit after receiving this many messages, writing the points buffered until then.
+    #[clap(long)]
+    max_messages: Option<u64>,
+
+    /// Exit after running for this long, e.g. `10m`, writing the points buffered until then.
+    #[clap(long, value_parser = humantime::parse_duration)]
+    run_duration: Option<Duration>,
+
+    /// Path to the mapping configuration file used to translate MQTT messages to InfluxDB2 points,
+    /// `-` for standard input, or an `http://` or `https://` URL to fetch it from.
     #[clap(env, long)]
     config: PathBuf,
 }
 
-#[tokio::main]
-async fn main() {
-    env_logger::init();
+impl RunArgs {
+    /// Topic filters to subscribe to besides those of the entries: those of `--debug-subscribe`,
+    /// and `#` with `--log-unmatched`.
+    fn extra_filters(&self) -> Vec<String> {
+        let mut filters = self.debug_subscribe.clone();
+        if self.log_unmatched {
+            filters.push("#".to_owned());
+        }
+        filters
+    }
+}
+
+/// Delay before the first attempt to reconnect to the MQTT server after a connection error.
+const MQTT_BACKOFF_MIN: Duration = Duration::from_secs(1);
+/// Time allowed for the MQTT disconnect to be sent when shutting down.
+const MQTT_DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Delay before pinging a sink again at startup, doubling up to `STARTUP_BACKOFF_MAX`.
+const STARTUP_BACKOFF_MIN: Duration = Duration::from_millis(500);
+const STARTUP_BACKOFF_MAX: Duration = Duration::from_secs(10);
+/// Minimum time between two log lines for the same unmatched topic with `--log-unmatched`.
+const UNMATCHED_LOG_INTERVAL: Duration = Duration::from_secs(60);
+/// Minimum time between two log lines for the same topic with `--debug-subscribe`.
+const DEBUG_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Replace the password in `url`, if any, so it can be logged safely.
+fn redact_password(url: &Url) -> Url {
+    let mut url = url.clone();
+    if url.password().is_some() {
+        let _ = url.set_password(Some("***"));
+    }
+    url
+}
+
+/// Whether every topic matching the MQTT filter `specific` also matches `general`.
+fn filter_covers(general: &str, specific: &str) -> bool {
+    // Wildcards in the first level do not match topics starting with `$`.
+    if specific.starts_with('$') && general.starts_with(['+', '#']) {
+        return false;
+    }
+
+    let mut specific = specific.split('/');
+    for level in general.split('/') {
+        match (level, specific.next()) {
+            ("#", _) => return true,
+            ("+", Some(s)) if s != "#" => {}
+            (g, Some(s)) if g == s => {}
+            _ => return false,
+        }
+    }
+    specific.next().is_none()
+}
+
+/// The subscriptions to request on a broker, and through which of them every entry receives its
+/// messages.
+struct Subscriptions {
+    requested: Vec<Subscription>,
+    /// Identifier of the subscription of every entry, by entry index, or `None` for entries on
+    /// other brokers.
+    routes: Vec<Option<usize>>,
+}
+
+/// The subscriptions for the entries on `broker`, or on the broker given with `--mqtt-url`, and
+/// to the `extra` filters that no entry needs.
+///
+/// Entries with the same filter share a subscription, with the highest QoS among them. With
+/// `consolidate`, filters covered by a more general one, such as `sensors/+/temp` by
+/// `sensors/#`, are left out as well. Every subscription has its position plus one as
+/// subscription identifier, so with MQTT v5 messages can be routed to their entries by it.
+fn subscriptions(
+    configuration: &Configuration,
+    broker: Option<&str>,
+    extra: &[String],
+    consolidate: bool,
+) -> Subscriptions {
+    let mut candidates: Vec<_> = configuration
+        .entries
+        .iter()
+        .filter(|e| e.broker.as_deref() == broker)
+        .map(|e| (e.src_filter().into_owned(), e.qos, e.no_local))
+        .collect();
+    for filter in extra {
+        candidates.push((filter.clone(), rumqttc::QoS::AtMostOnce, false));
+    }
+
+    let mut requested: Vec<Subscription> = Vec::new();
+    for (filter, _, no_local) in &candidates {
+        let covered = consolidate
+            && candidates.iter().any(|(other, _, other_no_local)| {
+                other != filter && other_no_local == no_local && filter_covers(other, filter)
+            });
+        let duplicate = requested
+            .iter()
+            .any(|s| &s.filter == filter && s.no_local == *no_local);
+        if !covered && !duplicate {
+            requested.push(Subscription {
+                filter: filter.clone(),
+                qos: rumqttc::QoS::AtMostOnce,
+                id: requested.len() + 1,
+                no_local: *no_local,
+            });
+        }
+    }
+
+    // The subscription that covers a filter, which exists for all candidates.
+    let covering = |requested: &[Subscription], filter: &str, no_local: bool| {
+        requested
+            .iter()
+            .position(|s| {
+                s.no_local == no_local
+                    && (s.filter == filter || consolidate && filter_covers(&s.filter, filter))
+            })
+            .unwrap()
+    };
+    for (filter, qos, no_local) in &candidates {
+        let i = covering(&requested, filter, *no_local);
+        if *qos > requested[i].qos {
+            requested[i].qos = *qos;
+        }
+    }
+    let routes = configuration
+        .entries
+        .iter()
+        .map(|e| {
+            (e.broker.as_deref() == broker)
+                .then(|| requested[covering(&requested, &e.src_filter(), e.no_local)].id)
+        })
+        .collect();
+
+    Subscriptions { requested, routes }
+}
+
+/// The topic filters of subscribe requests to a broker, in the order they are sent, as the
+/// server only refers to a request by its packet identifier when answering it.
+#[derive(Default)]
+struct SubscribeRequests {
+    /// Held while queueing requests, so those queued from different tasks are not interleaved.
+    queueing: tokio::sync::Mutex<()>,
+    /// Filters of the requests that were queued but not sent yet.
+    queued: Mutex<VecDeque<String>>,
+}
+
+/// Print the subscriptions for every broker as a table, with the measurements of the entries
+/// that receive their messages.
+fn print_subscriptions(
+    args: &RunArgs,
+    configuration: &Configuration,
+    brokers: &[(Option<String>, Broker)],
+) {
+    let mut rows = vec![[
+        "BROKER".to_owned(),
+        "FILTER".to_owned(),
+        "QOS".to_owned(),
+        "MEASUREMENTS".to_owned(),
+    ]];
+    for (name, broker) in brokers {
+        let subscriptions = subscriptions(
+            configuration,
+            name.as_deref(),
+            &args.extra_filters(),
+            args.mqtt_consolidate_subscriptions,
+        );
+        for subscription in &subscriptions.requested {
+            let mut measurements = Vec::new();
+            for (entry, route) in configuration.entries.iter().zip(&subscriptions.routes) {
+                if *route == Some(subscription.id) {
+                    measurements.extend(entry.routes.iter().map(|r| r.dst_name.as_str()));
+                    measurements.push(entry.dst_name.as_str());
+                }
+            }
+            measurements.dedup();
+            rows.push([
+                redact_password(&broker.url).to_string(),
+                subscription.filter.clone(),
+                (subscription.qos as u8).to_string(),
+                if measurements.is_empty() {
+                    "(only logged)".to_owned()
+                } else {
+                    measurements.join(", ")
+                },
+            ]);
+        }
+    }
+
+    let mut widths = [0; 3];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for [broker, filter, qos, measurements] in &rows {
+        println!(
+            "{:bw$}  {:fw$}  {:qw$}  {}",
+            broker,
+            filter,
+            qos,
+            measurements,
+            bw = widths[0],
+            fw = widths[1],
+            qw = widths[2]
+        );
+    }
+}
+
+/// Subscribe to `subscriptions`.
+///
+/// The subscribe requests are queued from a separate task, as they are only sent out while the
+/// event loop is being polled.
+fn subscribe(
+    client: &mqtt::Client,
+    requests: &Arc<SubscribeRequests>,
+    subscriptions: Vec<Subscription>,
+) {
+    resubscribe(client, requests, &[], subscriptions);
+}
+
+/// Change the subscriptions from `old` to `new`, only unsubscribing from filters that are gone
+/// and subscribing to those that are new or changed, so retained messages are not sent again for
+/// unchanged subscriptions.
+///
+/// Failing to queue a request is logged rather than fatal, so the other filters are still
+/// subscribed to.
+fn resubscribe(
+    client: &mqtt::Client,
+    requests: &Arc<SubscribeRequests>,
+    old: &[Subscription],
+    new: Vec<Subscription>,
+) {
+    let client = client.clone();
+    let requests = requests.clone();
+    let removed: Vec<_> = old
+        .iter()
+        .filter(|o| !new.iter().any(|n| n.filter == o.filter))
+        .map(|o| o.filter.clone())
+        .collect();
+    let changed: Vec<_> = new.into_iter().filter(|n| !old.contains(n)).collect();
+
+    tokio::spawn(async move {
+        let _queueing = requests.queueing.lock().await;
+        for filter in removed {
+            if let Err(e) = client.unsubscribe(filter.clone()).await {
+                log::error!("Failed to unsubscribe from {}: {}", filter, e);
+            }
+        }
+        for subscription in changed {
+            let filter = subscription.filter.clone();
+            requests.queued.lock().unwrap().push_back(filter.clone());
+            if let Err(e) = client.subscribe(subscription).await {
+                requests.queued.lock().unwrap().pop_back();
+                log::error!("Failed to subscribe to {}: {}", filter, e);
+            }
+        }
+    });
+}
 
-    let args = Args::parse();
+/// Send to `reloads` whenever SIGHUP is received or, with a `watcher`, the configuration file
+/// changed.
+///
+/// This runs in a separate task, as the polling in between would be restarted every time the
+/// event loop receives something.
+async fn watch_reloads(mut watcher: Option<file_watch::FileWatcher>, reloads: mpsc::Sender<()>) {
+    loop {
+        match &mut watcher {
+            Some(watcher) => tokio::select! {
+                _ = signal::reload_requested() => {}
+                _ = watcher.changed() => {}
+            },
+            None => signal::reload_requested().await,
+        }
+        if reloads.send(()).await.is_err() {
+            return;
+        }
+    }
+}
 
-    let configuration =
-        serde_yaml::from_reader::<_, Configuration>(File::open(args.config).unwrap()).unwrap();
+/// Log the routes of all entries and the warnings about the configuration.
+fn log_configuration(configuration: &Configuration) {
+    for entry in configuration.entries.iter() {
+        log::info!("Route {}", entry);
+    }
+    for warning in configuration.warnings() {
+        log::warn!("{}", warning);
+    }
+}
+
+/// The `count`th heartbeat point, subject to the same checks as the points from messages.
+fn heartbeat_point(
+    args: &RunArgs,
+    configuration: &Configuration,
+    cardinality: &mut Option<CardinalityGuard>,
+    count: i64,
+    uptime: Duration,
+) -> Result<Point<'static>, String> {
+    let mut point = Point::new(args.heartbeat_measurement.clone())
+        .add_field("count", Value::Integer(count))
+        .add_field("uptime", Value::Float(uptime.as_secs_f64()));
+    for (name, value) in &configuration.global_tags {
+        point = point.add_tag(name, Value::String(value.clone().into()));
+    }
+    point = args.name_policy.apply(point)?;
+    if let Some(cardinality) = cardinality {
+        point = cardinality.check(point);
+    }
+    Ok(point)
+}
 
-    let mut mqtt_url = args.mqtt_url.clone();
+/// Leave out the points without fields, which would fail the whole batch they are written in.
+fn skip_points_without_fields<'a>(
+    points: Vec<Point<'a>>,
+    topic: &str,
+    metrics: &Metrics,
+) -> Vec<Point<'a>> {
+    let count = points.len();
+    let points: Vec<_> = points
+        .into_iter()
+        .filter(|point| !point.fields.is_empty())
+        .collect();
+    if points.len() < count {
+        log::warn!(
+            "Skipping {} points without fields from message on {}",
+            count - points.len(),
+            topic
+        );
+        metrics.points_without_fields(count - points.len());
+    }
+    points
+}
+
+/// Load the configuration at `path` again, or `None` to keep using `current` if it is invalid or
+/// changes the brokers, which requires a restart.
+async fn reload(path: &Path, current: &Configuration) -> Option<Configuration> {
+    if path == Path::new("-") {
+        log::error!("Cannot reload configuration from standard input");
+        return None;
+    }
+    let configuration = match Configuration::load(path).await {
+        Ok(configuration) => configuration,
+        Err(e) => {
+            log::error!(
+                "Failed to reload configuration, keeping the current one: {}",
+                e
+            );
+            return None;
+        }
+    };
+    if configuration.brokers != current.brokers {
+        log::error!("Changing brokers requires a restart, keeping the current configuration");
+        return None;
+    }
+
+    log::info!("Reloaded configuration from {}", path.display());
+    log_configuration(&configuration);
+    Some(configuration)
+}
+
+/// Options to connect to `broker` with, taking everything but its url, client ID and credentials
+/// from the `--mqtt-*` arguments.
+fn mqtt_options(args: &RunArgs, broker: &Broker) -> mqtt::Options {
+    let mut mqtt_url = broker.url.clone();
+    let client_id = broker.client_id.as_ref().unwrap_or(&args.mqtt_client_id);
     mqtt_url
         .query_pairs_mut()
-        .append_pair("client_id", &args.mqtt_client_id);
+        .append_pair("client_id", client_id);
+
+    log::debug!("Connecting to MQTT server: {}", redact_password(&mqtt_url));
+
+    let mqtt_tls = matches!(mqtt_url.scheme(), "mqtts" | "ssl");
+    let mut options = match mqtt::Options::parse_url(args.mqtt_version, mqtt_url.as_str()) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("Invalid MQTT url {}: {}", redact_password(&broker.url), e);
+            std::process::exit(1);
+        }
+    };
+
+    if mqtt_tls {
+        let client_auth = args
+            .mqtt_client_cert
+            .as_deref()
+            .zip(args.mqtt_client_key.as_deref());
+
+        match tls::client_config(args.mqtt_ca_cert.as_deref(), client_auth) {
+            Ok(config) => {
+                options.set_transport(Transport::tls_with_config(config.into()));
+            }
+            Err(e) => {
+                eprintln!("Failed to set up MQTT TLS: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if args.mqtt_ca_cert.is_some() || args.mqtt_client_cert.is_some() {
+        eprintln!(
+            "MQTT TLS certificates were given, but the MQTT url {} is not mqtts://",
+            redact_password(&broker.url)
+        );
+        std::process::exit(1);
+    }
+    options.set_keep_alive(Duration::from_secs(args.mqtt_keep_alive));
+    options.set_clean_session(args.mqtt_clean_session);
+
+    if let Some(topic) = &args.mqtt_will_topic {
+        log::debug!("Setting MQTT last will on {}", topic);
+        let qos = rumqttc::qos(args.mqtt_will_qos).unwrap();
+        options.set_last_will(topic.clone(), args.mqtt_will_payload.clone(), qos);
+    }
+
+    if let Some(username) = &broker.username {
+        log::debug!("Authenticating with MQTT server as {}", username);
+        options.set_credentials(
+            username.clone(),
+            broker.password.clone().unwrap_or_default(),
+        );
+    }
+
+    options
+}
 
-    log::debug!("Connecting to MQTT server: {}", mqtt_url);
+/// An event of the connection to the broker with the given index.
+type BrokerEvent = (usize, Result<mqtt::Event, mqtt::ConnectionError>);
 
-    let mut options = MqttOptions::parse_url(mqtt_url).unwrap();
-    options.set_keep_alive(Duration::from_secs(5));
+/// Poll the event loop of broker `index` and pass its events on to `events`, until a requested
+/// disconnect was sent.
+///
+/// Whenever the connection is (re-)established, the current `subscriptions` are subscribed to. After a
+/// connection error, reconnecting is attempted with exponential backoff up to `max_backoff`.
+async fn connection(
+    index: usize,
+    max_backoff: Duration,
+    url: Url,
+    (client, mut eventloop): (mqtt::Client, mqtt::EventLoop),
+    subscriptions: watch::Receiver<Vec<Subscription>>,
+    requests: Arc<SubscribeRequests>,
+    events: mpsc::Sender<BrokerEvent>,
+) {
+    let min_backoff = MQTT_BACKOFF_MIN.min(max_backoff);
+    let mut backoff = min_backoff;
+    loop {
+        let event = eventloop.poll().await;
+        match &event {
+            Ok(mqtt::Event::Connected) => {
+                log::info!("Connected to MQTT server {}", url);
+                subscribe(&client, &requests, subscriptions.borrow().clone());
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!(
+                "MQTT connection error on {}: {}, retrying in {:?}",
+                url,
+                e,
+                backoff
+            ),
+        }
+
+        let disconnected = matches!(event, Ok(mqtt::Event::Disconnected));
+        let failed = event.is_err();
+        if events.send((index, event)).await.is_err() || disconnected {
+            return;
+        }
+
+        if failed {
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(max_backoff);
+        } else {
+            backoff = min_backoff;
+        }
+    }
+}
+
+/// Build the HTTP client to talk to InfluxDB with, authenticating with `jwt` and trusting the
+/// certificates given on the command line.
+fn influxdb_http_client(args: &RunArgs, jwt: &str) -> reqwest::Client {
+    let mut authorization = match HeaderValue::from_str(&format!("Token {}", jwt)) {
+        Ok(authorization) => authorization,
+        Err(_) => {
+            eprintln!("InfluxDB token contains invalid characters");
+            std::process::exit(1);
+        }
+    };
+    authorization.set_sensitive(true);
+    let headers = HeaderMap::from_iter([(AUTHORIZATION, authorization)]);
+    let mut builder = reqwest::Client::builder().default_headers(headers);
+
+    let https = args.influxdb_url.scheme() == "https";
+    if let Some(path) = &args.influxdb_ca_cert {
+        let certificate = std::fs::read(path)
+            .map_err(|e| e.to_string())
+            .and_then(|pem| reqwest::Certificate::from_pem(&pem).map_err(|e| e.to_string()));
+        match certificate {
+            Ok(certificate) => builder = builder.add_root_certificate(certificate),
+            Err(e) => {
+                eprintln!(
+                    "Failed to read InfluxDB CA certificate from {}: {}",
+                    path.display(),
+                    e
+                );
+                std::process::exit(1);
+            }
+        }
+        if https {
+            log::info!(
+                "Verifying the InfluxDB certificate against the system roots and {}",
+                path.display()
+            );
+        }
+    } else if args.influxdb_insecure {
+        builder = builder.danger_accept_invalid_certs(true);
+        if https {
+            log::warn!("Not verifying the InfluxDB certificate, as --influxdb-insecure is set");
+        }
+    } else if https {
+        log::info!("Verifying the InfluxDB certificate against the system roots");
+    }
+
+    match builder.build() {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Failed to set up the InfluxDB client: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Connect to the InfluxDB server given on the command line, exiting if it is misconfigured.
+async fn connect_influxdb(args: &RunArgs) -> sink::InfluxDb {
+    let jwt = match (args.influxdb_jwt.clone(), &args.influxdb_jwt_file) {
+        (Some(jwt), _) => jwt,
+        (None, Some(path)) => match std::fs::read_to_string(path) {
+            Ok(jwt) => jwt.trim_end_matches(['\r', '\n']).to_owned(),
+            Err(e) => {
+                eprintln!(
+                    "Failed to read InfluxDB token from {}: {}",
+                    path.display(),
+                    e
+                );
+                std::process::exit(1);
+            }
+        },
+        (None, None) if args.influxdb_version == InfluxdbVersion::V1 => String::new(),
+        (None, None) => {
+            eprintln!("--influxdb-jwt or --influxdb-jwt-file is required with InfluxDB 2");
+            std::process::exit(1);
+        }
+    };
 
     log::debug!("Connecting to InfluxDB server: {}", args.influxdb_url);
 
-    let influxdb = influxdb_rs::Client::new(
-        args.influxdb_url,
-        args.influxdb_bucket,
-        args.influxdb_org,
-        args.influxdb_jwt,
+    let org = match (&args.influxdb_org, args.influxdb_version) {
+        (Some(org), InfluxdbVersion::V2) if org.is_empty() => {
+            eprintln!("--influxdb-org must not be empty");
+            std::process::exit(1);
+        }
+        (Some(org), _) => org.clone(),
+        (None, InfluxdbVersion::V1) => String::new(),
+        (None, InfluxdbVersion::V2) => {
+            eprintln!("--influxdb-org is required with InfluxDB 2");
+            std::process::exit(1);
+        }
+    };
+    let bucket = match &args.influxdb_bucket {
+        Some(bucket) if bucket.is_empty() => {
+            eprintln!("--influxdb-bucket must not be empty");
+            std::process::exit(1);
+        }
+        Some(bucket) => bucket,
+        None => {
+            eprintln!("--influxdb-bucket is required with --sink influxdb");
+            std::process::exit(1);
+        }
+    };
+    let deadline = args
+        .influxdb_startup_timeout
+        .map(|timeout| tokio::time::Instant::now() + timeout);
+    let mut backoff = STARTUP_BACKOFF_MIN;
+    let mut influxdb = influxdb_rs::Client::new_with_client(
+        args.influxdb_url.clone(),
+        bucket.clone(),
+        org,
+        influxdb_http_client(args, &jwt),
     )
-    .await
-    .unwrap();
+    .set_jwt_token(jwt);
+    loop {
+        let result = match args.influxdb_version {
+            InfluxdbVersion::V2 => influxdb.get_org_id().await,
+            // InfluxDB 1 has no organizations to look up.
+            InfluxdbVersion::V1 => Ok(String::new()),
+        };
+        match result {
+            Ok(org_id) => {
+                influxdb.org_id = org_id;
+                break;
+            }
+            // Looking up the organization fails while InfluxDB is still starting.
+            Err(e)
+                if deadline
+                    .is_some_and(|deadline| tokio::time::Instant::now() + backoff < deadline) =>
+            {
+                log::warn!(
+                    "Failed to connect to InfluxDB: {}, retrying in {:?}",
+                    e,
+                    backoff
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(STARTUP_BACKOFF_MAX);
+            }
+            Err(e) => {
+                eprintln!("Failed to connect to InfluxDB: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let sink = sink::InfluxDb {
+        client: influxdb,
+        api: match args.influxdb_version {
+            InfluxdbVersion::V1 => sink::Api::V1 {
+                username: args.influxdb_username.clone(),
+                password: args.influxdb_password.clone(),
+            },
+            InfluxdbVersion::V2 => sink::Api::V2,
+        },
+        gzip: args.influxdb_gzip,
+    };
+
+    if let Some(deadline) = deadline {
+        if tokio::time::timeout_at(deadline, wait_for_ping(&sink))
+            .await
+            .is_err()
+        {
+            eprintln!(
+                "InfluxDB did not respond within {}",
+                humantime::format_duration(args.influxdb_startup_timeout.unwrap())
+            );
+            std::process::exit(1);
+        }
+    }
+    sink
+}
 
-    if let Ok(true) = influxdb.ping().await.await {
-        log::info!("Successfully pinged InfluxDB");
+/// Ping `sink` until it responds, backing off between attempts.
+async fn wait_for_ping(sink: &dyn PointSink) {
+    let mut backoff = STARTUP_BACKOFF_MIN;
+    while !sink.ping().await {
+        log::warn!("Failed to ping {}, retrying in {:?}", sink.name(), backoff);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(STARTUP_BACKOFF_MAX);
+    }
+}
+
+fn parse_precision(s: &str) -> Precision {
+    match s {
+        "s" => Precision::Seconds,
+        "ms" => Precision::Milliseconds,
+        "us" => Precision::Microseconds,
+        "ns" => Precision::Nanoseconds,
+        _ => unreachable!("rejected by the argument parser"),
+    }
+}
+
+async fn validate(args: ValidateArgs) -> ExitCode {
+    let configuration = match Configuration::load(&args.config).await {
+        Ok(configuration) => configuration,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    for entry in configuration.entries.iter() {
+        println!("{}", entry);
+    }
+
+    let warnings = configuration.warnings();
+    for warning in warnings.iter() {
+        eprintln!("Warning: {}", warning);
+    }
+
+    println!(
+        "{}: {} entries, {} warnings",
+        args.config.display(),
+        configuration.entries.len(),
+        warnings.len()
+    );
+
+    if args.strict && !warnings.is_empty() {
+        ExitCode::FAILURE
     } else {
-        log::error!("Failed to ping InfluxDB");
+        ExitCode::SUCCESS
     }
+}
 
-    let (mqtt_client, mut mqtt_eventloop) = AsyncClient::new(options, 10);
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    init_logging(cli.log_format);
 
-    for e in configuration.entries.iter() {
-        mqtt_client
-            .subscribe(&e.src_topic, QoS::AtMostOnce)
-            .await
-            .unwrap();
+    match cli.command {
+        Command::Run(args) => run(*args).await,
+        Command::Validate(args) => validate(args).await,
     }
+}
 
-    let mut last_message = BTreeMap::<String, Instant>::new();
+async fn run(args: RunArgs) -> ExitCode {
+    let mut configuration = match Configuration::load(&args.config).await {
+        Ok(configuration) => configuration,
+        Err(e) => {
+            eprintln!("Failed to load configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    log_configuration(&configuration);
+
+    // The broker given with `--mqtt-url` comes first, followed by those in the configuration.
+    let default_broker = Broker {
+        url: args.mqtt_url.clone(),
+        client_id: None,
+        username: args.mqtt_username.clone(),
+        password: args.mqtt_password.clone(),
+    };
+    let brokers: Vec<(Option<String>, Broker)> = std::iter::once((None, default_broker))
+        .chain(
+            configuration
+                .brokers
+                .iter()
+                .map(|(name, broker)| (Some(name.clone()), broker.clone())),
+        )
+        .collect();
+    if args.config_check {
+        print_subscriptions(&args, &configuration, &brokers);
+        return ExitCode::SUCCESS;
+    }
+    let options: Vec<_> = brokers
+        .iter()
+        .map(|(_, broker)| mqtt_options(&args, broker))
+        .collect();
+
+    if args.mqtt_version == mqtt::Version::V3 && configuration.entries.iter().any(|e| e.no_local) {
+        log::warn!("no_local is only supported with MQTT v5, ignoring it");
+    }
+
+    let health = Arc::new(Health::new(if args.dry_run { 0 } else { args.sink.len() }));
+    if let Some(addr) = args.health_addr {
+        if let Err(e) = health::spawn(addr, health.clone()) {
+            eprintln!("Failed to serve health checks on {}: {}", addr, e);
+            std::process::exit(1);
+        }
+    }
+
+    let metrics = Arc::new(Metrics::default());
+    if let Some(addr) = args.metrics_addr {
+        if let Err(e) = metrics::spawn(addr, metrics.clone()) {
+            eprintln!("Failed to serve metrics on {}: {}", addr, e);
+            std::process::exit(1);
+        }
+    }
+    tokio::spawn(metrics::log_drops(metrics.clone(), args.drop_log_interval));
+    if let Some(interval) = args.stats_interval {
+        tokio::spawn(metrics::log_stats(metrics.clone(), interval));
+    }
+
+    let mut sinks: Vec<(Option<Box<dyn PointSink>>, BTreeMap<_, _>)> = Vec::new();
+    let kinds: &[SinkKind] = if args.dry_run {
+        log::info!("Dry run, points will be logged instead of written to InfluxDB");
+        sinks.push((None, BTreeMap::new()));
+        &[]
+    } else {
+        &args.sink
+    };
+    for (index, kind) in kinds.iter().enumerate() {
+        if kinds[..index].contains(kind) {
+            eprintln!("Every kind of --sink can only be given once");
+            std::process::exit(1);
+        }
+        let sink: Box<dyn PointSink> = match kind {
+            SinkKind::Influxdb => Box::new(connect_influxdb(&args).await),
+            SinkKind::File => {
+                let Some(path) = &args.sink_path else {
+                    eprintln!("--sink-path is required with --sink file");
+                    std::process::exit(1);
+                };
+                match sink::File::open(path) {
+                    Ok(file) => Box::new(file),
+                    Err(e) => {
+                        eprintln!("Failed to open {}: {}", path.display(), e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+        };
+        if sink.ping().await {
+            log::info!("Writing points to {}", sink.name());
+            health.set_sink_ok(index, true);
+        } else {
+            log::error!("Failed to ping {}", sink.name());
+        }
+
+        // One spool per target, so spooled points are replayed to the bucket they were meant for.
+        let mut spools = BTreeMap::new();
+        if let (Some(dir), SinkKind::Influxdb) = (&args.spool_dir, kind) {
+            let targets = configuration.entries.iter().map(|entry| entry.target());
+            for target in std::iter::once(WriteTarget::default()).chain(targets) {
+                if let Entry::Vacant(vacant) = spools.entry(target) {
+                    match Spool::open(dir, vacant.key(), args.spool_max_bytes) {
+                        Ok(spool) => {
+                            vacant.insert(spool);
+                        }
+                        Err(e) => {
+                            eprintln!("Failed to open spool in {}: {}", dir.display(), e);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+            }
+        }
+        sinks.push((Some(sink), spools));
+    }
+
+    // Every sink has its own writer, so a sink that fails or is slow does not hold up the others.
+    let mut queues = Queues::new(metrics.clone());
+    let mut writers = Vec::new();
+    for (index, (sink, spools)) in sinks.into_iter().enumerate() {
+        let name = sink
+            .as_ref()
+            .map_or("the log", |sink| sink.name())
+            .to_owned();
+        let writer = Writer {
+            sink,
+            precision: args.influxdb_precision,
+            batch_size: args.influxdb_batch_size,
+            flush_interval: args.influxdb_flush_interval,
+            max_retries: args.influxdb_max_retries,
+            retry_delay: args.influxdb_retry_delay,
+            spools,
+            health: health.clone(),
+            index,
+            metrics: metrics.clone(),
+        };
+        // Leave room for a few batches, so no points are dropped while a batch is written.
+        let (points_tx, points_rx) = mpsc::channel(4 * args.influxdb_batch_size.max(1));
+        queues.add(name, points_tx);
+        writers.push(tokio::spawn(writer.run(points_rx)));
+    }
+
+    let (events_tx, mut events) = mpsc::channel(args.mqtt_channel_capacity as usize);
+    let mut clients = Vec::new();
+    let mut routes = Vec::new();
+    let mut connections = Vec::new();
+    for (index, options) in options.into_iter().enumerate() {
+        let (name, broker) = &brokers[index];
+        let (client, eventloop) = mqtt::connect(options, args.mqtt_channel_capacity as usize);
+        let subscriptions = subscriptions(
+            &configuration,
+            name.as_deref(),
+            &args.extra_filters(),
+            args.mqtt_consolidate_subscriptions,
+        );
+        log::debug!(
+            "Subscribing to {} filters on {}",
+            subscriptions.requested.len(),
+            redact_password(&broker.url)
+        );
+        routes.push(subscriptions.routes);
+        let (subscriptions_tx, subscriptions_rx) = watch::channel(subscriptions.requested);
+        let requests = Arc::new(SubscribeRequests::default());
+        connections.push(tokio::spawn(connection(
+            index,
+            args.mqtt_reconnect_max_delay,
+            redact_password(&broker.url),
+            (client.clone(), eventloop),
+            subscriptions_rx,
+            requests.clone(),
+            events_tx.clone(),
+        )));
+        clients.push((client, subscriptions_tx, requests));
+    }
+    // Filters of the subscribe requests sent to every broker, by packet identifier.
+    let mut subscribes_sent = vec![HashMap::new(); brokers.len()];
+    drop(events_tx);
+    let mut connected = vec![false; brokers.len()];
+    // Whether the current connection to a broker is not the first one.
+    let mut connected_before = vec![false; brokers.len()];
+    let mut reconnected = vec![false; brokers.len()];
+    // Failed attempts to connect to every broker since it was last connected.
+    let mut failed_attempts = vec![0; brokers.len()];
+    let mut exit_code = ExitCode::SUCCESS;
+
+    let mut last_message = BTreeMap::<usize, Instant>::new();
+    let mut deduplicator = Deduplicator::default();
+    let mut cardinality = args
+        .max_tag_values
+        .map(|max_values| CardinalityGuard::new(max_values, args.tag_cardinality_action));
+    let mut last_write = HashMap::<usize, HashMap<String, Instant>>::new();
+    let mut last_unmatched = HashMap::<String, Instant>::new();
+    let mut last_debug = HashMap::<String, Instant>::new();
+
+    signal::install();
+    let terminated = signal::terminated();
+    tokio::pin!(terminated);
+    let is_file = args.config != Path::new("-") && !config::is_url(&args.config);
+    if args.watch_config && !is_file {
+        log::warn!("--watch-config only watches files, reload with SIGHUP instead");
+    }
+    let config_watcher =
+        (args.watch_config && is_file).then(|| file_watch::FileWatcher::new(args.config.clone()));
+    let (reloads_tx, mut reloads) = mpsc::channel(1);
+    tokio::spawn(watch_reloads(config_watcher, reloads_tx));
+
+    let run_duration = async {
+        match args.run_duration {
+            Some(run_duration) => tokio::time::sleep(run_duration).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(run_duration);
+    let mut received = 0;
+    let started = Instant::now();
+    let mut heartbeat = args.heartbeat_interval.map(tokio::time::interval);
+    let mut heartbeats = 0;
 
     loop {
-        let notification = mqtt_eventloop.poll().await.unwrap();
+        if args.max_messages.is_some_and(|max| received >= max) {
+            log::info!("Received {} messages", received);
+            break;
+        }
+
+        let (broker, notification) = tokio::select! {
+            _ = &mut terminated => break,
+            _ = &mut run_duration => {
+                log::info!(
+                    "Ran for {}",
+                    humantime::format_duration(args.run_duration.unwrap())
+                );
+                break;
+            }
+            Some(()) = reloads.recv() => {
+                let Some(reloaded) = reload(&args.config, &configuration).await else {
+                    continue;
+                };
+                for (index, (((client, subscriptions_tx, requests), (name, _)), routes)) in
+                    clients.iter().zip(&brokers).zip(&mut routes).enumerate()
+                {
+                    let new = subscriptions(
+                        &reloaded,
+                        name.as_deref(),
+                        &args.extra_filters(),
+                        args.mqtt_consolidate_subscriptions,
+                    );
+                    resubscribe(
+                        client,
+                        requests,
+                        &subscriptions_tx.borrow(),
+                        new.requested.clone(),
+                    );
+                    health.retain_subscriptions(index, |filter| {
+                        new.requested.iter().any(|s| s.filter == filter)
+                    });
+                    subscriptions_tx.send_replace(new.requested);
+                    *routes = new.routes;
+                }
+                configuration = reloaded;
+                // State is kept by entry index, which may now refer to another entry.
+                last_message.clear();
+                last_write.clear();
+                deduplicator = Deduplicator::default();
+                continue;
+            }
+            _ = async { heartbeat.as_mut().unwrap().tick().await }, if heartbeat.is_some() => {
+                heartbeats += 1;
+                let point = match heartbeat_point(
+                    &args,
+                    &configuration,
+                    &mut cardinality,
+                    heartbeats,
+                    started.elapsed(),
+                ) {
+                    Ok(point) => point,
+                    Err(reason) => {
+                        log::error!("Not writing heartbeat: {}", reason);
+                        continue;
+                    }
+                };
+                queues.send(QueuedPoint {
+                    target: WriteTarget::default(),
+                    topic: "heartbeat".to_owned(),
+                    point,
+                });
+                continue;
+            }
+            event = events.recv() => match event {
+                Some(event) => event,
+                None => break,
+            },
+        };
+
+        let notification = match notification {
+            Ok(notification) => notification,
+            Err(_) => {
+                connected[broker] = false;
+                health.set_mqtt_connected(false);
+                metrics.set_mqtt_connected(false);
+                failed_attempts[broker] += 1;
+                if args
+                    .mqtt_reconnect_max_attempts
+                    .is_some_and(|max| failed_attempts[broker] >= max)
+                {
+                    log::error!(
+                        "Giving up on MQTT server {} after {} failed attempts",
+                        redact_password(&brokers[broker].1.url),
+                        failed_attempts[broker]
+                    );
+                    exit_code = ExitCode::FAILURE;
+                    break;
+                }
+                continue;
+            }
+        };
 
         match notification {
-            rumqttc::Event::Incoming(Packet::ConnAck(ConnAck {
-                code: ConnectReturnCode::Success,
-                ..
-            })) => {
-                log::info!("Connected to MQTT");
-            }
-            rumqttc::Event::Incoming(Packet::Publish(Publish { topic, payload, .. })) => {
-                if let Some(entry) = configuration
-                    .entries
-                    .iter()
-                    .find(|e| matches(&topic, &e.src_topic))
+            mqtt::Event::Connected => {
+                failed_attempts[broker] = 0;
+                // Requests sent on an earlier connection will not be answered anymore.
+                subscribes_sent[broker].clear();
+                reconnected[broker] = connected_before[broker];
+                connected_before[broker] = true;
+                connected[broker] = true;
+                let all_connected = connected.iter().all(|&connected| connected);
+                health.set_mqtt_connected(all_connected);
+                metrics.set_mqtt_connected(all_connected);
+            }
+            mqtt::Event::Message(message) => {
+                let Message {
+                    topic,
+                    payload,
+                    subscription_ids,
+                    ..
+                } = &message;
+                let _topic = log_context::set("topic", topic);
+                metrics.message_received(topic);
+                received += 1;
+                if payload.len() > args.max_payload_bytes {
+                    log::warn!(
+                        "Dropping message on {}: payload of {} bytes exceeds the maximum of {}",
+                        topic,
+                        payload.len(),
+                        args.max_payload_bytes
+                    );
+                    metrics.message_dropped(DropReason::TooLarge);
+                    continue;
+                }
+                log::debug!(
+                    "Message on {} ({} bytes): {:?}",
+                    topic,
+                    payload.len(),
+                    config::preview(payload)
+                );
+
+                // Matched on the topic rather than the subscription identifiers, as the message
+                // may only have been delivered for the catch-all subscription.
+                if args.log_unmatched
+                    && !configuration.entries.iter().any(|e| {
+                        e.broker.as_deref() == brokers[broker].0.as_deref()
+                            && e.accepts_topic(topic)
+                    })
                 {
-                    let last_message = last_message.entry(entry.src_topic.clone());
+                    let last = last_unmatched.get(topic);
+                    if last.is_none_or(|last| last.elapsed() >= UNMATCHED_LOG_INTERVAL) {
+                        log::info!("No entry for message on {}", topic);
+                        last_unmatched.insert(topic.clone(), Instant::now());
+                    }
+                }
+
+                let debug = args
+                    .debug_subscribe
+                    .iter()
+                    .any(|filter| matches(topic, filter));
+                if debug {
+                    let last = last_debug.get(topic);
+                    if last.is_none_or(|last| last.elapsed() >= DEBUG_LOG_INTERVAL) {
+                        log::info!(
+                            "Debug message on {} ({} bytes): {:?}",
+                            topic,
+                            payload.len(),
+                            config::preview(payload)
+                        );
+                        last_debug.insert(topic.clone(), Instant::now());
+                    }
+                }
+
+                let mut matched = false;
+                for (i, entry) in configuration.entries.iter().enumerate().filter(|(i, e)| {
+                    // Without subscription identifiers, as with v3, only the topic is matched.
+                    let subscribed = match routes[broker][*i] {
+                        Some(id) => subscription_ids.is_empty() || subscription_ids.contains(&id),
+                        None => false,
+                    };
+                    subscribed && e.accepts_topic(topic)
+                }) {
+                    matched = true;
+                    let _measurement = log_context::set("measurement", entry.measurement(topic));
+
+                    if message.retain && reconnected[broker] && entry.ignore_retained_on_resubscribe
+                    {
+                        log::debug!("Skipping retained message on {} after reconnecting", topic);
+                        metrics.message_dropped(DropReason::Retained);
+                        continue;
+                    }
+
+                    let last_message = last_message.entry(i);
 
                     match last_message {
                         Entry::Vacant(instant) => {
                             instant.insert(Instant::now());
-                        },
+                        }
                         Entry::Occupied(mut instant) => {
                             let instant = instant.get_mut();
 
-                            if let Some(throttle_ms) =  entry.throttle_ms {
+                            if let Some(throttle_ms) = entry.throttle_ms {
                                 let throttle = Duration::from_millis(throttle_ms);
 
                                 if *instant + throttle > Instant::now() {
+                                    metrics.message_dropped(DropReason::Throttled);
                                     continue; // We still need to wait before we can accept a new message.
                                 }
                             }
 
                             *instant = Instant::now();
-                        },
+                        }
                     }
 
-                    let point = Point::new(&entry.dst_name);
-                    let point = entry.fields.extract(&payload, point);
-                    log::info!("Received {:?}", point);
+                    if let Some(min_interval) = entry.min_interval {
+                        let last = last_write.get(&i).and_then(|topics| topics.get(topic));
+                        if last.is_some_and(|last| last.elapsed() < min_interval) {
+                            metrics.message_dropped(DropReason::Throttled);
+                            continue;
+                        }
+                    }
 
-                    if let Err(e) = influxdb
-                        .write_point(point, Some(Precision::Milliseconds), None)
-                        .await
+                    let extracted = match entry
+                        .to_points(&message, entry.precision.unwrap_or(args.influxdb_precision))
                     {
-                        log::error!("Failed to query InfluxDB: {:?}", e);
+                        Ok(Some(points)) => points,
+                        Ok(None) => {
+                            log::debug!("Skipping message on {}, filter or routes not met", topic);
+                            metrics.message_dropped(DropReason::Filtered);
+                            continue;
+                        }
+                        Err(e) => {
+                            if let ExtractError::InvalidJson { .. } = e {
+                                metrics.json_parse_error();
+                            }
+                            log::warn!("Dropping message on {}: {}", topic, e);
+                            metrics.message_dropped(DropReason::Parse);
+                            continue;
+                        }
+                    };
+                    if let Some(dedupe) = entry.dedupe {
+                        if deduplicator.is_duplicate(
+                            i,
+                            topic,
+                            dedupe,
+                            entry.dedupe_ttl,
+                            payload,
+                            &extracted,
+                        ) {
+                            log::debug!("Skipping repeated message on {}", topic);
+                            metrics.message_dropped(DropReason::Duplicate);
+                            continue;
+                        }
+                    }
+
+                    let extracted = extracted.into_iter().map(|mut point| {
+                        for (name, value) in &configuration.global_tags {
+                            point
+                                .tags
+                                .entry(name.clone())
+                                .or_insert_with(|| Value::String(value.clone().into()));
+                        }
+                        args.name_policy.apply(point)
+                    });
+                    let extracted = match extracted.collect::<Result<Vec<_>, _>>() {
+                        Ok(extracted) => extracted,
+                        Err(reason) => {
+                            log::warn!("Dropping message on {}: {}", topic, reason);
+                            metrics.message_dropped(DropReason::InvalidName);
+                            continue;
+                        }
+                    };
+                    if extracted.is_empty() {
+                        log::debug!("No points in message on {}", topic);
                     }
+                    let count = extracted.len();
+                    let extracted = skip_points_without_fields(extracted, topic, &metrics);
+                    if count > 0 && extracted.is_empty() {
+                        metrics.message_dropped(DropReason::NoFields);
+                        continue;
+                    }
+
+                    if entry.min_interval.is_some() {
+                        last_write
+                            .entry(i)
+                            .or_default()
+                            .insert(topic.clone(), Instant::now());
+                    }
+
+                    let target = entry.target();
+                    for mut point in extracted {
+                        if let Some(cardinality) = &mut cardinality {
+                            point = cardinality.check(point);
+                        }
+                        log::info!("Received on {} for entries[{}]: {:?}", topic, i, point);
+                        metrics.point_received();
+                        queues.send(QueuedPoint {
+                            target: target.clone(),
+                            topic: topic.clone(),
+                            point,
+                        });
+                    }
+                }
+                if !matched && !debug {
+                    metrics.message_dropped(DropReason::Unmatched);
                 }
             }
-            rumqttc::Event::Incoming(_) => {}
-            rumqttc::Event::Outgoing(_) => {}
+            mqtt::Event::SubscribeSent(pkid) => {
+                let (_, _, requests) = &clients[broker];
+                if let Some(filter) = requests.queued.lock().unwrap().pop_front() {
+                    subscribes_sent[broker].insert(pkid, filter);
+                }
+            }
+            mqtt::Event::SubAck { pkid, rejected } => {
+                let Some(filter) = subscribes_sent[broker].remove(&pkid) else {
+                    continue;
+                };
+                match &rejected {
+                    Some(reason) => log::error!(
+                        "Subscription to {} on {} was rejected ({}), its messages will not be \
+                         received",
+                        filter,
+                        redact_password(&brokers[broker].1.url),
+                        reason
+                    ),
+                    None => log::debug!("Subscribed to {}", filter),
+                }
+                health.set_subscription_ok(broker, &filter, rejected.is_none());
+            }
+            mqtt::Event::Disconnected | mqtt::Event::Other => {}
+        }
+    }
+
+    log::info!("Shutting down");
+
+    // The disconnects are only sent while the event loops are polled, which requires their
+    // events to be received until the connections end. Brokers that cannot be reached will not
+    // see a disconnect, so their connections are ended right away.
+    let disconnect = async {
+        for (index, (client, _, _)) in clients.iter().enumerate() {
+            if connected[index] {
+                let _ = client.disconnect().await;
+            } else {
+                connections[index].abort();
+            }
+        }
+    };
+    let drain = async {
+        while let Some((index, event)) = events.recv().await {
+            if event.is_err() {
+                connections[index].abort();
+            }
+        }
+    };
+    if tokio::time::timeout(MQTT_DISCONNECT_TIMEOUT, async {
+        tokio::join!(disconnect, drain)
+    })
+    .await
+    .is_err()
+    {
+        log::warn!("Timed out disconnecting from MQTT");
+    }
+
+    // Closing the channels makes the writers flush the points they still have.
+    drop(queues);
+    for writer in writers {
+        if let Err(e) = writer.await {
+            log::error!("Writer failed: {}", e);
         }
     }
+    exit_code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_args(args: &[&str]) -> RunArgs {
+        let cli = Cli::parse_from(
+            ["mqtt2influxdb", "run", "--config", "config.yaml"]
+                .iter()
+                .chain(args),
+        );
+        match cli.command {
+            Command::Run(args) => *args,
+            Command::Validate(_) => unreachable!(),
+        }
+    }
+
+    fn configuration(yaml: &str) -> Configuration {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn heartbeat_names_follow_the_name_policy() {
+        let configuration = configuration("{global_tags: {host: pi one}, entries: []}");
+
+        let args = run_args(&["--heartbeat-measurement", "bridge heartbeat"]);
+        let point = heartbeat_point(&args, &configuration, &mut None, 1, Duration::ZERO).unwrap();
+        assert_eq!(point.measurement, "bridge heartbeat");
+        assert_eq!(point.fields["count"], Value::Integer(1));
+
+        let args = run_args(&[
+            "--heartbeat-measurement",
+            "bridge heartbeat",
+            "--name-policy",
+            "sanitize",
+        ]);
+        let point = heartbeat_point(&args, &configuration, &mut None, 1, Duration::ZERO).unwrap();
+        assert_eq!(point.measurement, "bridge_heartbeat");
+
+        let args = run_args(&[
+            "--heartbeat-measurement",
+            "bridge heartbeat",
+            "--name-policy",
+            "reject",
+        ]);
+        assert!(heartbeat_point(&args, &configuration, &mut None, 1, Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn heartbeat_tags_count_towards_max_tag_values() {
+        let args = run_args(&["--max-tag-values", "1", "--tag-cardinality-action", "drop"]);
+        let mut cardinality = args
+            .max_tag_values
+            .map(|max_values| CardinalityGuard::new(max_values, args.tag_cardinality_action));
+
+        let before = configuration("{global_tags: {host: one}, entries: []}");
+        let point = heartbeat_point(&args, &before, &mut cardinality, 1, Duration::ZERO).unwrap();
+        assert!(point.tags.contains_key("host"));
+
+        // A reload that changes a global tag gives it another value.
+        let after = configuration("{global_tags: {host: two}, entries: []}");
+        let point = heartbeat_point(&args, &after, &mut cardinality, 2, Duration::ZERO).unwrap();
+        assert!(!point.tags.contains_key("host"));
+    }
+
+    #[test]
+    fn points_without_fields_are_skipped_and_counted() {
+        let metrics = Metrics::default();
+        let points = vec![
+            Point::new("m").add_field("t", Value::Float(21.5)),
+            Point::new("m"),
+            Point::new("m").add_tag("site", Value::String("home".into())),
+        ];
+
+        let points = skip_points_without_fields(points, "sensors/kitchen", &metrics);
+        assert_eq!(points.len(), 1);
+        assert_eq!(points[0].fields["t"], Value::Float(21.5));
+        assert!(metrics
+            .render()
+            .contains("\nmqtt2influxdb_points_without_fields_total 2\n"));
+
+        assert!(skip_points_without_fields(Vec::new(), "sensors/kitchen", &metrics).is_empty());
+        assert!(metrics
+            .render()
+            .contains("\nmqtt2influxdb_points_without_fields_total 2\n"));
+    }
 }
@@ -0,0 +1,415 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+
+use crate::http;
+
+/// Why a message did not result in a point.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DropReason {
+    /// The payload exceeded `--max-payload-bytes`.
+    TooLarge,
+    /// No entry matched the topic.
+    Unmatched,
+    /// An entry's `throttle_ms` or `min_interval` had not passed yet.
+    Throttled,
+    /// An entry's `filter` was not met.
+    Filtered,
+    /// The payload could not be parsed or mapped by an entry.
+    Parse,
+    /// An entry's `dedupe` found the message to be repeated.
+    Duplicate,
+    /// A name or tag value was rejected by `--name-policy`.
+    InvalidName,
+    /// A retained message was sent again after reconnecting, for an entry with
+    /// `ignore_retained_on_resubscribe`.
+    Retained,
+    /// None of the points of the message had fields, as none of the fields of an entry were
+    /// found in the payload, and InfluxDB rejects points without fields. The points themselves
+    /// are counted by `Metrics::points_without_fields`.
+    NoFields,
+}
+
+impl DropReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DropReason::TooLarge => "too_large",
+            DropReason::Unmatched => "unmatched",
+            DropReason::Throttled => "throttled",
+            DropReason::Filtered => "filtered",
+            DropReason::Parse => "parse",
+            DropReason::Duplicate => "duplicate",
+            DropReason::InvalidName => "invalid_name",
+            DropReason::Retained => "retained",
+            DropReason::NoFields => "no_fields",
+        }
+    }
+}
+
+/// Upper bounds of the buckets of the write latency histogram, in seconds.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Durations counted per bucket of `LATENCY_BUCKETS`, so percentiles can be estimated without
+/// keeping every observation.
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative, with a last bucket for those above all bounds.
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let i = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn counts(&self) -> Vec<u64> {
+        self.buckets
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect()
+    }
+}
+
+/// The upper bound of the bucket that the `q` quantile of `counts` falls in, or `None` if it
+/// is above all bounds.
+fn quantile(counts: &[u64], q: f64) -> Option<f64> {
+    let rank = (q * counts.iter().sum::<u64>() as f64).ceil() as u64;
+    let mut seen = 0;
+    for (bound, count) in LATENCY_BUCKETS.iter().zip(counts) {
+        seen += count;
+        if seen >= rank.max(1) {
+            return Some(*bound);
+        }
+    }
+    None
+}
+
+/// Counters describing the work done by the bridge, exposed in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    messages_received: Mutex<BTreeMap<String, u64>>,
+    messages_dropped: Mutex<BTreeMap<DropReason, u64>>,
+    points_received: AtomicU64,
+    points_written: AtomicU64,
+    points_dropped: AtomicU64,
+    points_without_fields: AtomicU64,
+    batches_written: AtomicU64,
+    write_duration: Histogram,
+    write_errors: AtomicU64,
+    json_parse_errors: AtomicU64,
+    mqtt_connected: AtomicBool,
+}
+
+impl Metrics {
+    pub fn message_received(&self, topic: &str) {
+        let mut messages_received = self.messages_received.lock().unwrap();
+        match messages_received.get_mut(topic) {
+            Some(count) => *count += 1,
+            None => {
+                messages_received.insert(topic.to_owned(), 1);
+            }
+        }
+    }
+
+    /// Count a message that was skipped by an entry, or by all of them, for `reason`.
+    pub fn message_dropped(&self, reason: DropReason) {
+        *self
+            .messages_dropped
+            .lock()
+            .unwrap()
+            .entry(reason)
+            .or_default() += 1;
+    }
+
+    /// Number of messages dropped so far for every reason that occurred.
+    pub fn messages_dropped(&self) -> BTreeMap<DropReason, u64> {
+        self.messages_dropped.lock().unwrap().clone()
+    }
+
+    /// Count a point extracted from a message and queued for writing.
+    pub fn point_received(&self) {
+        self.points_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a batch of `count` points that was written in a request taking `duration`.
+    pub fn points_written(&self, count: usize, duration: Duration) {
+        self.points_written
+            .fetch_add(count as u64, Ordering::Relaxed);
+        self.batches_written.fetch_add(1, Ordering::Relaxed);
+        self.write_duration.observe(duration);
+    }
+
+    /// Count a point that was not queued for a sink, as its queue was full.
+    pub fn point_dropped(&self) {
+        self.points_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count `count` points that were skipped as they had no fields.
+    pub fn points_without_fields(&self, count: usize) {
+        self.points_without_fields
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn write_error(&self) {
+        self.write_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn json_parse_error(&self) {
+        self.json_parse_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_mqtt_connected(&self, connected: bool) {
+        self.mqtt_connected.store(connected, Ordering::Relaxed);
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let mut counter = |name: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+
+        counter(
+            "mqtt2influxdb_points_received_total",
+            "Points extracted from MQTT messages and queued for writing.",
+            self.points_received.load(Ordering::Relaxed),
+        );
+        counter(
+            "mqtt2influxdb_points_written_total",
+            "Points successfully written to InfluxDB.",
+            self.points_written.load(Ordering::Relaxed),
+        );
+        counter(
+            "mqtt2influxdb_points_dropped_total",
+            "Points not queued for a sink, as it was not keeping up.",
+            self.points_dropped.load(Ordering::Relaxed),
+        );
+        counter(
+            "mqtt2influxdb_points_without_fields_total",
+            "Points skipped as they had no fields, which InfluxDB rejects.",
+            self.points_without_fields.load(Ordering::Relaxed),
+        );
+        counter(
+            "mqtt2influxdb_batches_written_total",
+            "Successful write requests to InfluxDB.",
+            self.batches_written.load(Ordering::Relaxed),
+        );
+        counter(
+            "mqtt2influxdb_write_errors_total",
+            "Failed write requests to InfluxDB.",
+            self.write_errors.load(Ordering::Relaxed),
+        );
+        counter(
+            "mqtt2influxdb_json_parse_errors_total",
+            "Received payloads that were not valid JSON.",
+            self.json_parse_errors.load(Ordering::Relaxed),
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP mqtt2influxdb_messages_received_total MQTT messages received per topic."
+        );
+        let _ = writeln!(out, "# TYPE mqtt2influxdb_messages_received_total counter");
+        for (topic, count) in self.messages_received.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "mqtt2influxdb_messages_received_total{{topic=\"{}\"}} {}",
+                escape_label(topic),
+                count
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP mqtt2influxdb_messages_dropped_total MQTT messages that did not result in a \
+             point, per reason."
+        );
+        let _ = writeln!(out, "# TYPE mqtt2influxdb_messages_dropped_total counter");
+        for (reason, count) in self.messages_dropped.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "mqtt2influxdb_messages_dropped_total{{reason=\"{}\"}} {}",
+                reason.as_str(),
+                count
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP mqtt2influxdb_write_duration_seconds Duration of successful write requests \
+             to InfluxDB."
+        );
+        let _ = writeln!(out, "# TYPE mqtt2influxdb_write_duration_seconds histogram");
+        let counts = self.write_duration.counts();
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(&counts) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "mqtt2influxdb_write_duration_seconds_bucket{{le=\"{}\"}} {}",
+                bound, cumulative
+            );
+        }
+        let total: u64 = counts.iter().sum();
+        let _ = writeln!(
+            out,
+            "mqtt2influxdb_write_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            total
+        );
+        let _ = writeln!(
+            out,
+            "mqtt2influxdb_write_duration_seconds_sum {}",
+            self.write_duration.sum_micros.load(Ordering::Relaxed) as f64 / 1e6
+        );
+        let _ = writeln!(out, "mqtt2influxdb_write_duration_seconds_count {}", total);
+
+        let _ = writeln!(
+            out,
+            "# HELP mqtt2influxdb_mqtt_connected Whether the MQTT connection is up."
+        );
+        let _ = writeln!(out, "# TYPE mqtt2influxdb_mqtt_connected gauge");
+        let _ = writeln!(
+            out,
+            "mqtt2influxdb_mqtt_connected {}",
+            u8::from(self.mqtt_connected.load(Ordering::Relaxed))
+        );
+
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn respond(metrics: &Metrics, request: &Request<Body>) -> Response<Body> {
+    match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            let mut response = Response::new(Body::from(metrics.render()));
+            response.headers_mut().insert(
+                header::CONTENT_TYPE,
+                header::HeaderValue::from_static("text/plain; version=0.0.4"),
+            );
+            response
+        }
+        _ => http::status(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Bind to `addr` and serve `/metrics` from a separate task.
+pub fn spawn(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<(), hyper::Error> {
+    http::spawn(addr, move |request| respond(&metrics, request))?;
+    log::info!("Serving metrics on http://{}/metrics", addr);
+    Ok(())
+}
+
+/// Log how many messages were dropped, and why, every `interval`. Nothing is logged for
+/// intervals without drops.
+pub async fn log_drops(metrics: Arc<Metrics>, interval: Duration) {
+    let mut previous = BTreeMap::new();
+    let mut timer = tokio::time::interval(interval);
+    timer.tick().await;
+    loop {
+        timer.tick().await;
+        let current = metrics.messages_dropped();
+        let reasons: Vec<_> = current
+            .iter()
+            .map(|(reason, count)| (reason, count - previous.get(reason).unwrap_or(&0)))
+            .filter(|&(_, count)| count > 0)
+            .collect();
+        if !reasons.is_empty() {
+            log::info!(
+                "Dropped {} messages in the last {} ({})",
+                reasons.iter().map(|(_, count)| count).sum::<u64>(),
+                humantime::format_duration(interval),
+                reasons
+                    .iter()
+                    .map(|(reason, count)| format!("{}: {}", reason.as_str(), count))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        previous = current;
+    }
+}
+
+/// Log the rate of points received, the batches written and their latency every `interval`.
+/// Nothing is logged for intervals without any points.
+pub async fn log_stats(metrics: Arc<Metrics>, interval: Duration) {
+    let snapshot = |metrics: &Metrics| {
+        (
+            metrics.points_received.load(Ordering::Relaxed),
+            metrics.points_written.load(Ordering::Relaxed),
+            metrics.batches_written.load(Ordering::Relaxed),
+            metrics.write_duration.counts(),
+        )
+    };
+    let mut previous = snapshot(&metrics);
+    let mut timer = tokio::time::interval(interval);
+    timer.tick().await;
+    loop {
+        timer.tick().await;
+        let current = snapshot(&metrics);
+        let received = current.0 - previous.0;
+        let written = current.1 - previous.1;
+        let batches = current.2 - previous.2;
+        let counts: Vec<_> = current
+            .3
+            .iter()
+            .zip(&previous.3)
+            .map(|(c, p)| c - p)
+            .collect();
+        previous = current;
+        if received == 0 && batches == 0 {
+            continue;
+        }
+
+        let mut message = format!(
+            "Received {:.1} points/s in the last {}, wrote {} points in {} batches",
+            received as f64 / interval.as_secs_f64(),
+            humantime::format_duration(interval),
+            written,
+            batches
+        );
+        if batches > 0 {
+            let percentile = |q| match quantile(&counts, q) {
+                Some(bound) => format!("<= {}s", bound),
+                None => format!("> {}s", LATENCY_BUCKETS[LATENCY_BUCKETS.len() - 1]),
+            };
+            let _ = write!(
+                message,
+                " ({:.1} points per batch), write latency p50 {}, p90 {}, p99 {}",
+                written as f64 / batches as f64,
+                percentile(0.5),
+                percentile(0.9),
+                percentile(0.99)
+            );
+        }
+        log::info!("{}", message);
+    }
+}
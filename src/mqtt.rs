@@ -0,0 +1,330 @@
+//! A thin layer over the MQTT v3.1.1 and v5 clients of `rumqttc`, so the rest of the bridge does
+//! not need to care which protocol version is spoken.
+
+use std::{fmt, time::Duration};
+
+use bytes::Bytes;
+use clap::ValueEnum;
+use rumqttc::{v5, ConnectReturnCode, QoS, Transport};
+
+/// MQTT protocol version to connect with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Version {
+    /// MQTT v3.1.1.
+    V3,
+    /// MQTT v5.
+    V5,
+}
+
+pub enum Options {
+    V3(Box<rumqttc::MqttOptions>),
+    V5(Box<v5::MqttOptions>),
+}
+
+impl Options {
+    pub fn parse_url(version: Version, url: &str) -> Result<Options, Box<dyn std::error::Error>> {
+        Ok(match version {
+            Version::V3 => Options::V3(Box::new(rumqttc::MqttOptions::parse_url(url)?)),
+            Version::V5 => Options::V5(Box::new(v5::MqttOptions::parse_url(url)?)),
+        })
+    }
+
+    pub fn set_transport(&mut self, transport: Transport) {
+        match self {
+            Options::V3(options) => {
+                options.set_transport(transport);
+            }
+            Options::V5(options) => {
+                options.set_transport(transport);
+            }
+        }
+    }
+
+    pub fn set_keep_alive(&mut self, keep_alive: Duration) {
+        match self {
+            Options::V3(options) => {
+                options.set_keep_alive(keep_alive);
+            }
+            Options::V5(options) => {
+                options.set_keep_alive(keep_alive);
+            }
+        }
+    }
+
+    /// Whether the server should discard the session when the connection closes. Without a clean
+    /// session, the server keeps the subscriptions and queues messages of QoS 1 and 2 while
+    /// disconnected. With v5 the session is then kept indefinitely.
+    pub fn set_clean_session(&mut self, clean_session: bool) {
+        match self {
+            Options::V3(options) => {
+                options.set_clean_session(clean_session);
+            }
+            Options::V5(options) => {
+                options.set_clean_start(clean_session);
+                if !clean_session {
+                    let mut properties = options
+                        .connect_properties()
+                        .unwrap_or_else(v5::mqttbytes::v5::ConnectProperties::new);
+                    properties.session_expiry_interval = Some(u32::MAX);
+                    options.set_connect_properties(properties);
+                }
+            }
+        }
+    }
+
+    /// Have the server publish `payload` to `topic` when the connection is lost ungracefully.
+    pub fn set_last_will(&mut self, topic: String, payload: String, qos: QoS) {
+        match self {
+            Options::V3(options) => {
+                options.set_last_will(rumqttc::LastWill::new(topic, payload, qos, false));
+            }
+            Options::V5(options) => {
+                options.set_last_will(v5::mqttbytes::v5::LastWill::new(
+                    topic,
+                    payload,
+                    v5_qos(qos),
+                    false,
+                    None,
+                ));
+            }
+        }
+    }
+
+    pub fn set_credentials(&mut self, username: String, password: String) {
+        match self {
+            Options::V3(options) => {
+                options.set_credentials(username, password);
+            }
+            Options::V5(options) => {
+                options.set_credentials(username, password);
+            }
+        }
+    }
+}
+
+fn v5_qos(qos: QoS) -> v5::mqttbytes::QoS {
+    v5::mqttbytes::qos(qos as u8).unwrap()
+}
+
+fn v3_qos(qos: v5::mqttbytes::QoS) -> QoS {
+    rumqttc::qos(qos as u8).unwrap()
+}
+
+/// Handle to queue requests on the event loop with.
+#[derive(Clone)]
+pub enum Client {
+    V3(rumqttc::AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+/// A subscription to request from the server.
+#[derive(Clone, PartialEq)]
+pub struct Subscription {
+    pub filter: String,
+    pub qos: QoS,
+    /// Subscription identifier the server tags matching messages with. Only used with v5.
+    pub id: usize,
+    /// Do not receive messages published by this client. Only used with v5.
+    pub no_local: bool,
+}
+
+impl Client {
+    /// Queue a disconnect, after which the event loop ends with `Event::Disconnected`.
+    pub async fn disconnect(&self) -> Result<(), ClientError> {
+        match self {
+            Client::V3(client) => client.disconnect().await.map_err(ClientError::V3),
+            Client::V5(client) => client.disconnect().await.map_err(ClientError::V5),
+        }
+    }
+
+    pub async fn unsubscribe(&self, filter: String) -> Result<(), ClientError> {
+        match self {
+            Client::V3(client) => client.unsubscribe(filter).await.map_err(ClientError::V3),
+            Client::V5(client) => client.unsubscribe(filter).await.map_err(ClientError::V5),
+        }
+    }
+
+    pub async fn subscribe(&self, subscription: Subscription) -> Result<(), ClientError> {
+        match self {
+            Client::V3(client) => client
+                .subscribe(subscription.filter, subscription.qos)
+                .await
+                .map_err(ClientError::V3),
+            Client::V5(client) => {
+                let filter = v5::mqttbytes::v5::Filter {
+                    nolocal: subscription.no_local,
+                    ..v5::mqttbytes::v5::Filter::new(subscription.filter, v5_qos(subscription.qos))
+                };
+                let properties = v5::mqttbytes::v5::SubscribeProperties {
+                    id: Some(subscription.id),
+                    user_properties: Vec::new(),
+                };
+                client
+                    .subscribe_many_with_properties([filter], properties)
+                    .await
+                    .map_err(ClientError::V5)
+            }
+        }
+    }
+}
+
+pub enum EventLoop {
+    V3(Box<rumqttc::EventLoop>),
+    V5(Box<v5::EventLoop>),
+}
+
+/// Create a client and the event loop that drives it, with room for `cap` queued requests.
+pub fn connect(options: Options, cap: usize) -> (Client, EventLoop) {
+    match options {
+        Options::V3(options) => {
+            let (client, eventloop) = rumqttc::AsyncClient::new(*options, cap);
+            (Client::V3(client), EventLoop::V3(Box::new(eventloop)))
+        }
+        Options::V5(options) => {
+            let (client, eventloop) = v5::AsyncClient::new(*options, cap);
+            (Client::V5(client), EventLoop::V5(Box::new(eventloop)))
+        }
+    }
+}
+
+/// A message received from the server.
+pub struct Message {
+    pub topic: String,
+    pub payload: Bytes,
+    pub qos: QoS,
+    /// Whether the server sent a retained message on subscribing, rather than passing on a
+    /// message as it was published.
+    pub retain: bool,
+    /// Identifiers of the subscriptions the message matched. Always empty with v3.
+    pub subscription_ids: Vec<usize>,
+    /// User properties the publisher attached to the message. Always empty with v3.
+    pub user_properties: Vec<(String, String)>,
+}
+
+pub enum Event {
+    /// The connection to the server was (re-)established.
+    Connected,
+    Message(Message),
+    /// A subscribe request with this packet identifier was sent to the server.
+    SubscribeSent(u16),
+    /// The server answered the subscribe request with packet identifier `pkid`, with the reason
+    /// if it rejected the filter.
+    SubAck {
+        pkid: u16,
+        rejected: Option<String>,
+    },
+    /// A disconnect requested with `Client::disconnect` was sent.
+    Disconnected,
+    /// Anything else, which the bridge does not act on.
+    Other,
+}
+
+impl EventLoop {
+    pub async fn poll(&mut self) -> Result<Event, ConnectionError> {
+        Ok(match self {
+            EventLoop::V3(eventloop) => {
+                match eventloop.poll().await.map_err(ConnectionError::V3)? {
+                    rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(rumqttc::ConnAck {
+                        code: ConnectReturnCode::Success,
+                        ..
+                    })) => Event::Connected,
+                    rumqttc::Event::Incoming(rumqttc::Packet::Publish(publish)) => {
+                        Event::Message(Message {
+                            topic: publish.topic,
+                            payload: publish.payload,
+                            qos: publish.qos,
+                            retain: publish.retain,
+                            subscription_ids: Vec::new(),
+                            user_properties: Vec::new(),
+                        })
+                    }
+                    rumqttc::Event::Outgoing(rumqttc::Outgoing::Subscribe(pkid)) => {
+                        Event::SubscribeSent(pkid)
+                    }
+                    rumqttc::Event::Incoming(rumqttc::Packet::SubAck(suback)) => Event::SubAck {
+                        pkid: suback.pkid,
+                        rejected: suback
+                            .return_codes
+                            .contains(&rumqttc::SubscribeReasonCode::Failure)
+                            .then(|| "failure".to_owned()),
+                    },
+                    rumqttc::Event::Outgoing(rumqttc::Outgoing::Disconnect) => Event::Disconnected,
+                    _ => Event::Other,
+                }
+            }
+            EventLoop::V5(eventloop) => {
+                match eventloop.poll().await.map_err(ConnectionError::V5)? {
+                    v5::Event::Incoming(v5::Incoming::ConnAck(v5::mqttbytes::v5::ConnAck {
+                        code: v5::mqttbytes::v5::ConnectReturnCode::Success,
+                        ..
+                    })) => Event::Connected,
+                    v5::Event::Incoming(v5::Incoming::Publish(publish)) => {
+                        let properties = publish.properties.unwrap_or_default();
+                        Event::Message(Message {
+                            topic: String::from_utf8_lossy(&publish.topic).into_owned(),
+                            payload: publish.payload,
+                            qos: v3_qos(publish.qos),
+                            retain: publish.retain,
+                            subscription_ids: properties.subscription_identifiers,
+                            user_properties: properties.user_properties,
+                        })
+                    }
+                    v5::Event::Outgoing(rumqttc::Outgoing::Subscribe(pkid)) => {
+                        Event::SubscribeSent(pkid)
+                    }
+                    v5::Event::Incoming(v5::Incoming::SubAck(suback)) => Event::SubAck {
+                        pkid: suback.pkid,
+                        rejected: suback
+                            .return_codes
+                            .iter()
+                            .find(|code| {
+                                !matches!(code, v5::mqttbytes::v5::SubscribeReasonCode::Success(_))
+                            })
+                            .map(
+                                |code| match suback.properties.and_then(|p| p.reason_string) {
+                                    Some(reason) => format!("{:?}: {}", code, reason),
+                                    None => format!("{:?}", code),
+                                },
+                            ),
+                    },
+                    v5::Event::Outgoing(rumqttc::Outgoing::Disconnect) => Event::Disconnected,
+                    _ => Event::Other,
+                }
+            }
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    V3(rumqttc::ClientError),
+    V5(v5::ClientError),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::V3(e) => e.fmt(f),
+            ClientError::V5(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+#[derive(Debug)]
+pub enum ConnectionError {
+    V3(rumqttc::ConnectionError),
+    V5(v5::ConnectionError),
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionError::V3(e) => e.fmt(f),
+            ConnectionError::V5(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ConnectionError {}
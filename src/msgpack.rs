@@ -0,0 +1,276 @@
+//! Decoding of MessagePack payloads into JSON values, so they can be mapped like JSON payloads.
+//!
+//! Binary values become arrays of bytes and map keys that are not strings are converted to text.
+//! Extension types are not supported.
+
+use serde_json::{Map, Number, Value};
+
+/// Maximum nesting of arrays and maps, to bound recursion on hostile payloads.
+const MAX_DEPTH: usize = 128;
+
+struct Decoder<'b> {
+    bytes: &'b [u8],
+    pos: usize,
+}
+
+impl<'b> Decoder<'b> {
+    fn take(&mut self, n: usize) -> Result<&'b [u8], String> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| format!("unexpected end of data at byte {}", self.bytes.len()))?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn uint(&mut self, n: usize) -> Result<u64, String> {
+        Ok(self
+            .take(n)?
+            .iter()
+            .fold(0, |acc, &b| (acc << 8) | u64::from(b)))
+    }
+
+    fn int(&mut self, n: usize) -> Result<i64, String> {
+        let shift = 64 - 8 * n as u32;
+        Ok(((self.uint(n)? << shift) as i64) >> shift)
+    }
+
+    fn len(&mut self, n: usize) -> Result<usize, String> {
+        Ok(self.uint(n)? as usize)
+    }
+
+    fn str(&mut self, len: usize) -> Result<Value, String> {
+        let at = self.pos;
+        let bytes = self.take(len)?;
+        std::str::from_utf8(bytes)
+            .map(|s| Value::String(s.to_owned()))
+            .map_err(|_| format!("invalid UTF-8 in string at byte {}", at))
+    }
+
+    fn bin(&mut self, len: usize) -> Result<Value, String> {
+        Ok(Value::Array(
+            self.take(len)?.iter().map(|&b| Value::from(b)).collect(),
+        ))
+    }
+
+    fn float(x: f64) -> Value {
+        Number::from_f64(x).map_or(Value::Null, Value::Number)
+    }
+
+    fn array(&mut self, len: usize, depth: usize) -> Result<Value, String> {
+        (0..len)
+            .map(|_| self.value(depth + 1))
+            .collect::<Result<_, _>>()
+            .map(Value::Array)
+    }
+
+    fn map(&mut self, len: usize, depth: usize) -> Result<Value, String> {
+        let mut map = Map::new();
+        for _ in 0..len {
+            let key = match self.value(depth + 1)? {
+                Value::String(s) => s,
+                key => key.to_string(),
+            };
+            map.insert(key, self.value(depth + 1)?);
+        }
+        Ok(Value::Object(map))
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, String> {
+        if depth > MAX_DEPTH {
+            return Err(format!("nested deeper than {} levels", MAX_DEPTH));
+        }
+
+        let at = self.pos;
+        let marker = self.take(1)?[0];
+        Ok(match marker {
+            0x00..=0x7f => Value::from(marker),
+            0x80..=0x8f => self.map(usize::from(marker & 0x0f), depth)?,
+            0x90..=0x9f => self.array(usize::from(marker & 0x0f), depth)?,
+            0xa0..=0xbf => self.str(usize::from(marker & 0x1f))?,
+            0xc0 => Value::Null,
+            0xc2 => Value::Bool(false),
+            0xc3 => Value::Bool(true),
+            0xc4 => {
+                let len = self.len(1)?;
+                self.bin(len)?
+            }
+            0xc5 => {
+                let len = self.len(2)?;
+                self.bin(len)?
+            }
+            0xc6 => {
+                let len = self.len(4)?;
+                self.bin(len)?
+            }
+            0xca => Self::float(f64::from(f32::from_bits(self.uint(4)? as u32))),
+            0xcb => Self::float(f64::from_bits(self.uint(8)?)),
+            0xcc => Value::from(self.uint(1)?),
+            0xcd => Value::from(self.uint(2)?),
+            0xce => Value::from(self.uint(4)?),
+            0xcf => Value::from(self.uint(8)?),
+            0xd0 => Value::from(self.int(1)?),
+            0xd1 => Value::from(self.int(2)?),
+            0xd2 => Value::from(self.int(4)?),
+            0xd3 => Value::from(self.int(8)?),
+            0xd9 => {
+                let len = self.len(1)?;
+                self.str(len)?
+            }
+            0xda => {
+                let len = self.len(2)?;
+                self.str(len)?
+            }
+            0xdb => {
+                let len = self.len(4)?;
+                self.str(len)?
+            }
+            0xdc => {
+                let len = self.len(2)?;
+                self.array(len, depth)?
+            }
+            0xdd => {
+                let len = self.len(4)?;
+                self.array(len, depth)?
+            }
+            0xde => {
+                let len = self.len(2)?;
+                self.map(len, depth)?
+            }
+            0xdf => {
+                let len = self.len(4)?;
+                self.map(len, depth)?
+            }
+            0xe0..=0xff => Value::from(marker as i8),
+            0xc7..=0xc9 | 0xd4..=0xd8 => {
+                return Err(format!("unsupported extension type at byte {}", at))
+            }
+            0xc1 => return Err(format!("invalid marker 0xc1 at byte {}", at)),
+        })
+    }
+}
+
+/// Decode a single MessagePack value that makes up all of `bytes`.
+pub fn decode(bytes: &[u8]) -> Result<Value, String> {
+    let mut decoder = Decoder { bytes, pos: 0 };
+    let value = decoder.value(0)?;
+    if decoder.pos != bytes.len() {
+        return Err(format!("trailing data at byte {}", decoder.pos));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn fixints() {
+        assert_eq!(decode(&[0x00]), Ok(json!(0)));
+        assert_eq!(decode(&[0x7f]), Ok(json!(127)));
+        assert_eq!(decode(&[0xe0]), Ok(json!(-32)));
+        assert_eq!(decode(&[0xff]), Ok(json!(-1)));
+    }
+
+    #[test]
+    fn ints() {
+        assert_eq!(decode(&[0xcc, 0xff]), Ok(json!(255)));
+        assert_eq!(decode(&[0xcd, 0x01, 0x00]), Ok(json!(256)));
+        assert_eq!(
+            decode(&[0xcf, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]),
+            Ok(json!(u64::MAX))
+        );
+        assert_eq!(decode(&[0xd0, 0x80]), Ok(json!(-128)));
+        assert_eq!(decode(&[0xd1, 0xff, 0x7f]), Ok(json!(-129)));
+        assert_eq!(decode(&[0xd2, 0x80, 0, 0, 0]), Ok(json!(i32::MIN)));
+        assert_eq!(
+            decode(&[0xd3, 0x80, 0, 0, 0, 0, 0, 0, 0]),
+            Ok(json!(i64::MIN))
+        );
+    }
+
+    #[test]
+    fn floats() {
+        let mut float32 = vec![0xca];
+        float32.extend(1.5f32.to_be_bytes());
+        assert_eq!(decode(&float32), Ok(json!(1.5)));
+
+        let mut float64 = vec![0xcb];
+        float64.extend(21.3f64.to_be_bytes());
+        assert_eq!(decode(&float64), Ok(json!(21.3)));
+
+        // JSON cannot hold NaN.
+        let mut nan = vec![0xcb];
+        nan.extend(f64::NAN.to_be_bytes());
+        assert_eq!(decode(&nan), Ok(Value::Null));
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(decode(b"\xa3abc"), Ok(json!("abc")));
+        assert_eq!(decode(b"\xd9\x03abc"), Ok(json!("abc")));
+        assert_eq!(decode(b"\xda\x00\x03abc"), Ok(json!("abc")));
+        assert_eq!(decode(b"\xdb\x00\x00\x00\x03abc"), Ok(json!("abc")));
+
+        let long = "x".repeat(300);
+        let mut str16 = vec![0xda, 0x01, 0x2c];
+        str16.extend(long.as_bytes());
+        assert_eq!(decode(&str16), Ok(json!(long)));
+
+        assert!(decode(b"\xa2\xc3\x28").is_err());
+        assert!(decode(b"\xd9\x04abc").is_err());
+    }
+
+    #[test]
+    fn binary() {
+        assert_eq!(decode(b"\xc4\x02\x01\xff"), Ok(json!([1, 255])));
+    }
+
+    #[test]
+    fn maps_and_arrays() {
+        assert_eq!(
+            decode(b"\x82\xa1t\xcb\x40\x35\x4c\xcc\xcc\xcc\xcc\xcd\xa1h\x92\xc3\xc0"),
+            Ok(json!({"t": 21.3, "h": [true, null]}))
+        );
+
+        let mut array16 = vec![0xdc, 0x00, 0x11];
+        array16.extend([0x01; 17]);
+        assert_eq!(decode(&array16), Ok(json!(vec![1; 17])));
+
+        let mut map16 = vec![0xde, 0x00, 0x11];
+        for i in 0..17 {
+            map16.extend([0xa1, b'a' + i, i]);
+        }
+        let value = decode(&map16).unwrap();
+        assert_eq!(value.as_object().unwrap().len(), 17);
+        assert_eq!(value["q"], json!(16));
+
+        // Keys that are not strings become text.
+        assert_eq!(decode(b"\x81\x01\xc2"), Ok(json!({"1": false})));
+    }
+
+    #[test]
+    fn extensions_are_rejected() {
+        assert_eq!(
+            decode(b"\x91\xd4\x01\x00"),
+            Err("unsupported extension type at byte 1".to_owned())
+        );
+        assert!(decode(b"\xc7\x01\x01\x00").is_err());
+        assert!(decode(&[0xc1]).is_err());
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(decode(b"").is_err());
+        assert!(decode(b"\x92\x01").is_err());
+        assert_eq!(
+            decode(b"\x01\x02"),
+            Err("trailing data at byte 1".to_owned())
+        );
+        assert!(decode(&[0x91; MAX_DEPTH + 2]).is_err());
+        assert!(decode(&[0xdd, 0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+}
@@ -0,0 +1,51 @@
+//! Points as written to InfluxDB, like those of `influxdb_rs`, which cannot hold unsigned
+//! integers.
+
+use std::{borrow::Cow, collections::HashMap};
+
+/// Value of a tag or field.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value<'a> {
+    String(Cow<'a, str>),
+    Integer(i64),
+    /// Unsigned integer, which InfluxDB 2 supports for fields, such as counters beyond the range
+    /// of `i64`.
+    UnsignedInteger(u64),
+    Float(f64),
+    Boolean(bool),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point<'a> {
+    pub measurement: String,
+    pub tags: HashMap<String, Value<'a>>,
+    pub fields: HashMap<String, Value<'a>>,
+    /// Timestamp in the precision the point is written with, or `None` for the server time.
+    pub timestamp: Option<i64>,
+}
+
+impl<'a> Point<'a> {
+    pub fn new<T: Into<String>>(measurement: T) -> Point<'a> {
+        Point {
+            measurement: measurement.into(),
+            tags: HashMap::new(),
+            fields: HashMap::new(),
+            timestamp: None,
+        }
+    }
+
+    pub fn add_tag<T: Into<String>>(mut self, tag: T, value: Value<'a>) -> Point<'a> {
+        self.tags.insert(tag.into(), value);
+        self
+    }
+
+    pub fn add_field<T: Into<String>>(mut self, field: T, value: Value<'a>) -> Point<'a> {
+        self.fields.insert(field.into(), value);
+        self
+    }
+
+    pub fn add_timestamp(mut self, timestamp: i64) -> Point<'a> {
+        self.timestamp = Some(timestamp);
+        self
+    }
+}
@@ -0,0 +1,56 @@
+//! Handling of the signals that ask the bridge to shut down or to reload its configuration.
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+static TERMINATE: AtomicBool = AtomicBool::new(false);
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+/// How often to check whether a signal arrived.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+extern "C" fn on_terminate(_: libc::c_int) {
+    TERMINATE.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn on_reload(_: libc::c_int) {
+    RELOAD.store(true, Ordering::SeqCst);
+}
+
+/// Catch SIGTERM and SIGINT, so `terminated` completes instead of the process being killed, and
+/// SIGHUP, so `reload_requested` completes.
+pub fn install() {
+    let terminate = on_terminate as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    let reload = on_reload as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // SAFETY: the handlers only store to an atomic, which is async-signal-safe.
+    unsafe {
+        libc::signal(libc::SIGTERM, terminate);
+        libc::signal(libc::SIGINT, terminate);
+        libc::signal(libc::SIGHUP, reload);
+    }
+}
+
+/// Complete once SIGHUP has been received since the last time this completed.
+pub async fn reload_requested() {
+    while !RELOAD.swap(false, Ordering::SeqCst) {
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Complete once SIGTERM or SIGINT has been received.
+///
+/// Afterwards the default handlers are restored, so a second signal kills the process if the
+/// shutdown hangs.
+pub async fn terminated() {
+    while !TERMINATE.load(Ordering::SeqCst) {
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    // SAFETY: restoring the default disposition has no further requirements.
+    unsafe {
+        libc::signal(libc::SIGTERM, libc::SIG_DFL);
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+    }
+}
@@ -0,0 +1,367 @@
+//! Destinations that points are written to, so the writer does not depend on a particular one.
+
+use std::{
+    cmp::Ordering,
+    fmt,
+    fs::OpenOptions,
+    future::Future,
+    io::{self, Write},
+    path::Path,
+    pin::Pin,
+    sync::Mutex,
+    time::Duration,
+};
+
+use influxdb_rs::{error::ErrorKind, Precision};
+
+use crate::gzip;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Upper bound for a `Retry-After` delay, so a bogus header does not stall writing indefinitely.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(10 * 60);
+
+/// Failure to write to a sink.
+#[derive(Debug)]
+pub struct WriteError {
+    pub inner: influxdb_rs::Error,
+    /// How long the sink asked to wait before writing again, from a `Retry-After` header.
+    pub retry_after: Option<Duration>,
+}
+
+impl From<influxdb_rs::Error> for WriteError {
+    fn from(inner: influxdb_rs::Error) -> WriteError {
+        WriteError {
+            inner,
+            retry_after: None,
+        }
+    }
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match &self.inner.inner {
+            ErrorKind::SyntaxError(_) => "invalid line protocol",
+            ErrorKind::InvalidCredentials(_) => "not authorized",
+            ErrorKind::DataBaseDoesNotExist(_) => "bucket not found",
+            // Status 500, which InfluxDB 1 used for missing retention policies.
+            ErrorKind::RetentionPolicyDoesNotExist(_) => "server error",
+            ErrorKind::Communication(_) => "communication error",
+            ErrorKind::Unknown(_) => "unexpected response",
+        };
+        write!(f, "{}: {}", kind, self.inner)
+    }
+}
+
+/// Parse a `Retry-After` header, which holds either a number of seconds or an HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let delay = match value.parse::<u64>() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => {
+            let at = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+            (at.with_timezone(&chrono::Utc) - chrono::Utc::now())
+                .to_std()
+                .unwrap_or_default()
+        }
+    };
+    Some(delay.min(MAX_RETRY_AFTER))
+}
+
+/// Where and how to write points, with `None` for the organization or bucket the sink was set up
+/// with, or the precision the writer was set up with.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteTarget {
+    /// Organization the bucket belongs to. Only used by InfluxDB 2.
+    pub org: Option<String>,
+    pub bucket: Option<String>,
+    /// Precision of the timestamps of the points.
+    pub precision: Option<Precision>,
+}
+
+impl Ord for WriteTarget {
+    fn cmp(&self, other: &WriteTarget) -> Ordering {
+        let key = |t: &WriteTarget| {
+            (
+                t.org.clone(),
+                t.bucket.clone(),
+                t.precision.map(|p| p as u8),
+            )
+        };
+        key(self).cmp(&key(other))
+    }
+}
+
+impl PartialOrd for WriteTarget {
+    fn partial_cmp(&self, other: &WriteTarget) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for WriteTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.bucket {
+            Some(bucket) => write!(f, "bucket {}", bucket)?,
+            None => f.write_str("the default bucket")?,
+        }
+        if let Some(org) = &self.org {
+            write!(f, " of org {}", org)?;
+        }
+        if let Some(precision) = &self.precision {
+            write!(f, " with precision {}", precision.to_str())?;
+        }
+        Ok(())
+    }
+}
+
+/// Something that accepts points as line protocol.
+///
+/// Points are passed as line protocol rather than as `Point`s, so points that were spooled as
+/// line protocol can be written as they are.
+pub trait PointSink: Send + Sync {
+    /// Write `lines` of line protocol to `target` as a single batch.
+    fn write_points<'a>(
+        &'a self,
+        target: &'a WriteTarget,
+        lines: &'a str,
+        precision: Precision,
+    ) -> BoxFuture<'a, Result<(), WriteError>>;
+
+    /// Whether the sink is reachable, so spooled points can be replayed to it.
+    fn ping(&self) -> BoxFuture<'_, bool>;
+
+    /// Description of the sink for log messages.
+    fn name(&self) -> &str;
+}
+
+/// HTTP API of InfluxDB to write with.
+pub enum Api {
+    /// The `/api/v2/write` endpoint of InfluxDB 2, authenticated with the token of the client.
+    V2,
+    /// The `/write` endpoint of InfluxDB 1, with buckets naming a database or a database and
+    /// retention policy as in `telegraf/autogen`.
+    V1 {
+        username: Option<String>,
+        password: Option<String>,
+    },
+}
+
+/// Writes to an InfluxDB server over HTTP.
+pub struct InfluxDb {
+    pub client: influxdb_rs::Client,
+    pub api: Api,
+    /// Compress the bodies of write requests with gzip.
+    pub gzip: bool,
+}
+
+impl InfluxDb {
+    /// Equivalent to `Client::write_points`, which only accepts points and a single bucket.
+    async fn write_lines(
+        &self,
+        target: &WriteTarget,
+        lines: &str,
+        precision: Precision,
+    ) -> Result<(), WriteError> {
+        let influxdb = &self.client;
+        let bucket = target.bucket.as_deref().unwrap_or(&influxdb.bucket);
+        let url = match &self.api {
+            Api::V2 => {
+                let params = vec![
+                    ("bucket", bucket),
+                    ("org", target.org.as_deref().unwrap_or(&influxdb.org)),
+                    ("precision", precision.to_str()),
+                ];
+                influxdb.build_url("api/v2/write", Some(params)).await
+            }
+            Api::V1 { .. } => {
+                let (db, rp) = match bucket.split_once('/') {
+                    Some((db, rp)) => (db, Some(rp)),
+                    None => (bucket, None),
+                };
+                let precision = match &precision {
+                    Precision::Microseconds => "u",
+                    other => other.to_str(),
+                };
+                let mut params = vec![("db", db), ("precision", precision)];
+                params.extend(rp.map(|rp| ("rp", rp)));
+                influxdb.build_url("write", Some(params)).await
+            }
+        };
+
+        let mut request = influxdb.client.post(url);
+        // Rather than the `u` and `p` query parameters, which would end up in logged errors.
+        if let Api::V1 { username, password } = &self.api {
+            if username.is_some() || password.is_some() {
+                request = request
+                    .basic_auth(username.as_deref().unwrap_or_default(), password.as_deref());
+            }
+        }
+        request = if self.gzip {
+            request
+                .header("Content-Encoding", "gzip")
+                .body(gzip::compress(lines.as_bytes()))
+        } else {
+            request.body(lines.to_owned())
+        };
+        // Without the URL, so no query parameters end up in logged errors.
+        let error = |e: reqwest::Error| influxdb_rs::Error::from(e.without_url());
+        let res = request.send().await.map_err(error)?;
+        let status = res.status().as_u16();
+        let retry_after = res
+            .headers()
+            .get("Retry-After")
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_retry_after);
+        let message = res.text().await.map_err(error)?;
+
+        let inner = match status {
+            204 => return Ok(()),
+            400 => ErrorKind::SyntaxError(message),
+            401 | 403 => ErrorKind::InvalidCredentials(message),
+            404 => ErrorKind::DataBaseDoesNotExist(message),
+            500 => ErrorKind::RetentionPolicyDoesNotExist(message),
+            status => ErrorKind::Unknown(format!("Received status code {}", status)),
+        };
+        Err(WriteError {
+            inner: influxdb_rs::Error { inner },
+            retry_after,
+        })
+    }
+}
+
+impl PointSink for InfluxDb {
+    fn write_points<'a>(
+        &'a self,
+        target: &'a WriteTarget,
+        lines: &'a str,
+        precision: Precision,
+    ) -> BoxFuture<'a, Result<(), WriteError>> {
+        Box::pin(self.write_lines(target, lines, precision))
+    }
+
+    fn ping(&self) -> BoxFuture<'_, bool> {
+        Box::pin(async { matches!(self.client.ping().await.await, Ok(true)) })
+    }
+
+    fn name(&self) -> &str {
+        "InfluxDB"
+    }
+}
+
+/// Appends line protocol to a file or standard output, for debugging or to import later with
+/// `influx write`.
+///
+/// Targets are not recorded, so all points end up in the same file.
+pub struct File {
+    name: String,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl File {
+    /// Open `path` for appending, creating it if needed, or standard output for `-`.
+    pub fn open(path: &Path) -> io::Result<File> {
+        if path == Path::new("-") {
+            return Ok(File {
+                name: "standard output".to_owned(),
+                out: Mutex::new(Box::new(io::stdout())),
+            });
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(File {
+            name: path.display().to_string(),
+            out: Mutex::new(Box::new(file)),
+        })
+    }
+}
+
+impl PointSink for File {
+    fn write_points<'a>(
+        &'a self,
+        _target: &'a WriteTarget,
+        lines: &'a str,
+        _precision: Precision,
+    ) -> BoxFuture<'a, Result<(), WriteError>> {
+        Box::pin(async move {
+            let mut out = self.out.lock().unwrap();
+            out.write_all(lines.as_bytes())
+                .and_then(|()| out.flush())
+                .map_err(|e| {
+                    WriteError::from(influxdb_rs::Error {
+                        inner: ErrorKind::Communication(e.to_string()),
+                    })
+                })
+        })
+    }
+
+    fn ping(&self) -> BoxFuture<'_, bool> {
+        Box::pin(async { true })
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Read, net::TcpListener, thread};
+
+    use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+
+    /// Answer a single request with `204 No Content`, returning the request line and headers.
+    fn serve_once(listener: TcpListener) -> thread::JoinHandle<String> {
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        })
+    }
+
+    #[tokio::test]
+    async fn v1_credentials_are_not_in_the_url() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = serve_once(listener);
+
+        // As set up for InfluxDB 1, without a token.
+        let headers = HeaderMap::from_iter([(AUTHORIZATION, HeaderValue::from_static("Token "))]);
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .build()
+            .unwrap();
+        let sink = InfluxDb {
+            client: influxdb_rs::Client::new_with_client(
+                url.parse().unwrap(),
+                "telegraf",
+                "",
+                client,
+            ),
+            api: Api::V1 {
+                username: Some("user".to_owned()),
+                password: Some("secret".to_owned()),
+            },
+            gzip: false,
+        };
+        sink.write_lines(&WriteTarget::default(), "m v=1\n", Precision::Seconds)
+            .await
+            .unwrap();
+
+        let request = server.join().unwrap();
+        let request_line = request.lines().next().unwrap();
+        assert!(request_line.starts_with("POST /write?db=telegraf&precision=s "));
+        assert!(!request.contains("secret"));
+        // Base64 of `user:secret`.
+        assert!(request
+            .lines()
+            .any(|line| line.eq_ignore_ascii_case("authorization: Basic dXNlcjpzZWNyZXQ=")));
+    }
+}
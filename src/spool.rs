@@ -0,0 +1,178 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::sink::WriteTarget;
+
+fn encode(name: &str) -> String {
+    url::form_urlencoded::byte_serialize(name.as_bytes()).collect()
+}
+
+/// Points that could not be written to InfluxDB, kept on disk as line protocol until they can.
+pub struct Spool {
+    path: PathBuf,
+    max_bytes: u64,
+}
+
+impl Spool {
+    /// Open the spool for `target` in `dir`, creating the directory if needed. Any points left
+    /// over from a previous run are kept.
+    pub fn open(dir: &Path, target: &WriteTarget, max_bytes: u64) -> io::Result<Spool> {
+        fs::create_dir_all(dir)?;
+        // An `@` and a `~`, which are encoded in the names themselves, separate the organization
+        // and the precision.
+        let mut name = match (&target.org, &target.bucket) {
+            (None, None) => "spool".to_owned(),
+            (None, Some(bucket)) => format!("spool-{}", encode(bucket)),
+            (Some(org), bucket) => format!(
+                "spool-{}@{}",
+                encode(org),
+                encode(bucket.as_deref().unwrap_or_default())
+            ),
+        };
+        if let Some(precision) = &target.precision {
+            name = format!("{}~{}", name, precision.to_str());
+        }
+        name.push_str(".lp");
+        Ok(Spool {
+            path: dir.join(name),
+            max_bytes,
+        })
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        match fs::metadata(&self.path) {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len().map(|len| len == 0).unwrap_or(true)
+    }
+
+    /// All spooled lines, oldest first.
+    pub fn read(&self) -> io::Result<String> {
+        match fs::read_to_string(&self.path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+            result => result,
+        }
+    }
+
+    /// Replace the contents of the spool with `lines`.
+    pub fn replace(&self, lines: &str) -> io::Result<()> {
+        if lines.is_empty() {
+            return match fs::remove_file(&self.path) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                result => result,
+            };
+        }
+
+        let tmp = self.path.with_extension("lp.tmp");
+        fs::write(&tmp, lines)?;
+        fs::rename(&tmp, &self.path)
+    }
+
+    /// Add `lines` to the end of the spool, dropping the oldest lines if it would grow beyond
+    /// its maximum size.
+    pub fn append(&self, lines: &str) -> io::Result<()> {
+        if self.len()? + lines.len() as u64 <= self.max_bytes {
+            return OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?
+                .write_all(lines.as_bytes());
+        }
+
+        let mut contents = self.read()?;
+        contents.push_str(lines);
+
+        // Cut after a newline, which is never inside a multi-byte character.
+        let bytes = contents.as_bytes();
+        let excess = bytes.len() - self.max_bytes as usize;
+        let cut = if bytes[excess - 1] == b'\n' {
+            excess
+        } else {
+            bytes[excess..]
+                .iter()
+                .position(|&b| b == b'\n')
+                .map_or(bytes.len(), |i| excess + i + 1)
+        };
+
+        log::warn!(
+            "Spool {} is full, dropping the {} oldest points",
+            self.path.display(),
+            contents[..cut].lines().count()
+        );
+
+        self.replace(&contents[cut..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A spool in a directory of its own, removed again when the test is done.
+    struct TestSpool {
+        spool: Spool,
+        dir: PathBuf,
+    }
+
+    impl TestSpool {
+        fn new(name: &str, max_bytes: u64) -> TestSpool {
+            let dir =
+                std::env::temp_dir().join(format!("mqtt2influxdb-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            let spool = Spool::open(&dir, &WriteTarget::default(), max_bytes).unwrap();
+            TestSpool { spool, dir }
+        }
+    }
+
+    impl Drop for TestSpool {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn append_keeps_order() {
+        let test = TestSpool::new("spool-order", 1024);
+        assert!(test.spool.is_empty());
+
+        test.spool.append("m v=1i 1\nm v=2i 2\n").unwrap();
+        test.spool.append("m v=3i 3\n").unwrap();
+        assert!(!test.spool.is_empty());
+        assert_eq!(test.spool.read().unwrap(), "m v=1i 1\nm v=2i 2\nm v=3i 3\n");
+
+        test.spool.replace("").unwrap();
+        assert!(test.spool.is_empty());
+        assert_eq!(test.spool.read().unwrap(), "");
+    }
+
+    #[test]
+    fn append_drops_oldest_lines_beyond_the_cap() {
+        let test = TestSpool::new("spool-cap", 20);
+        test.spool.append("m v=1i 1\nm v=2i 2\n").unwrap();
+        test.spool.append("m v=3i 3\n").unwrap();
+        assert_eq!(test.spool.read().unwrap(), "m v=2i 2\nm v=3i 3\n");
+
+        // Exactly at the cap, nothing is dropped.
+        let test = TestSpool::new("spool-exact", 18);
+        test.spool.append("m v=1i 1\n").unwrap();
+        test.spool.append("m v=2i 2\n").unwrap();
+        assert_eq!(test.spool.read().unwrap(), "m v=1i 1\nm v=2i 2\n");
+    }
+
+    #[test]
+    fn append_cuts_between_multi_byte_characters() {
+        let test = TestSpool::new("spool-utf8", 17);
+        test.spool.append("m t=\"°C°C\"\n").unwrap();
+        // The cut would fall within the first degree sign.
+        test.spool.append("m t=\"°F\"\n").unwrap();
+        assert_eq!(test.spool.read().unwrap(), "m t=\"°F\"\n");
+    }
+}
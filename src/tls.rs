@@ -0,0 +1,75 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+};
+
+use rumqttc::tokio_rustls::rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore};
+
+fn invalid_data(path: &Path, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), reason),
+    )
+}
+
+fn open(path: &Path) -> io::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)?;
+    if certs.is_empty() {
+        return Err(invalid_data(path, "no PEM certificates found"));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKey> {
+    for item in rustls_pemfile::read_all(&mut open(path)?)? {
+        match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(invalid_data(path, "no PEM private key found"))
+}
+
+/// Build a TLS client configuration that trusts the certificates in `ca`, or the system root store
+/// when no CA is given, and optionally authenticates with a client certificate and key.
+pub fn client_config(
+    ca: Option<&Path>,
+    client_auth: Option<(&Path, &Path)>,
+) -> io::Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(ca) => {
+            for cert in load_certs(ca)? {
+                roots
+                    .add(&cert)
+                    .map_err(|e| invalid_data(ca, &e.to_string()))?;
+            }
+        }
+        None => {
+            for cert in rustls_native_certs::load_native_certs()? {
+                // Skip platform certificates that rustls does not understand.
+                let _ = roots.add(&Certificate(cert.0));
+            }
+        }
+    }
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+
+    match client_auth {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(|e| invalid_data(key, &e.to_string())),
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
@@ -0,0 +1,496 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use influxdb_rs::{error::ErrorKind, Precision};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    time::MissedTickBehavior,
+};
+
+use crate::{
+    health::Health,
+    line_protocol,
+    metrics::Metrics,
+    point::Point,
+    sink::{PointSink, WriteError, WriteTarget},
+    spool::Spool,
+};
+
+/// A point to write, received from the event loop.
+#[derive(Clone)]
+pub struct QueuedPoint {
+    pub target: WriteTarget,
+    /// Topic of the message the point was extracted from, to tell where failed points came from.
+    pub topic: String,
+    pub point: Point<'static>,
+}
+
+/// Queues of the writers of all sinks.
+pub struct Queues {
+    /// Queue of every writer, with the name of its sink and the number of points it missed
+    /// since its queue became full.
+    queues: Vec<(mpsc::Sender<QueuedPoint>, String, u64)>,
+    metrics: Arc<Metrics>,
+}
+
+impl Queues {
+    pub fn new(metrics: Arc<Metrics>) -> Queues {
+        Queues {
+            queues: Vec::new(),
+            metrics,
+        }
+    }
+
+    pub fn add(&mut self, name: String, queue: mpsc::Sender<QueuedPoint>) {
+        self.queues.push((queue, name, 0));
+    }
+
+    /// Queue `point` for every sink. A sink whose queue is full, as it is slow or retrying a
+    /// failed write, misses the point instead of holding up the event loop and the other sinks.
+    pub fn send(&mut self, point: QueuedPoint) {
+        for (queue, name, dropped) in &mut self.queues {
+            match queue.try_send(point.clone()) {
+                Ok(()) => {
+                    if *dropped > 0 {
+                        log::warn!(
+                            "Queue for {} accepts points again, {} points were dropped",
+                            name,
+                            dropped
+                        );
+                        *dropped = 0;
+                    }
+                }
+                Err(TrySendError::Full(_)) => {
+                    if *dropped == 0 {
+                        log::warn!(
+                            "Queue for {} is full, dropping points until it catches up",
+                            name
+                        );
+                    }
+                    *dropped += 1;
+                    self.metrics.point_dropped();
+                }
+                Err(TrySendError::Closed(_)) => log::error!("Writer stopped, dropping point"),
+            }
+        }
+    }
+}
+
+/// Points waiting to be written, with their topics, by where to write them.
+type Buffer = BTreeMap<WriteTarget, Vec<(String, Point<'static>)>>;
+
+/// Maximum number of topics to list when describing a batch.
+const MAX_TOPICS: usize = 5;
+
+/// What a batch of points holds, for log messages about writing it.
+struct Batch<'a> {
+    target: &'a WriteTarget,
+    points: &'a [(String, Point<'static>)],
+}
+
+impl fmt::Display for Batch<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut measurements = BTreeMap::<&str, usize>::new();
+        let mut topics = BTreeSet::new();
+        for (topic, point) in self.points {
+            *measurements.entry(&point.measurement).or_default() += 1;
+            topics.insert(topic.as_str());
+        }
+
+        write!(f, "{} points for {} (", self.points.len(), self.target)?;
+        for (i, (measurement, count)) in measurements.into_iter().enumerate() {
+            let separator = if i == 0 { "" } else { ", " };
+            write!(f, "{}{}: {}", separator, measurement, count)?;
+        }
+        f.write_str(") from ")?;
+        for (i, topic) in topics.iter().take(MAX_TOPICS).enumerate() {
+            let separator = if i == 0 { "" } else { ", " };
+            write!(f, "{}{}", separator, topic)?;
+        }
+        if topics.len() > MAX_TOPICS {
+            write!(f, " and {} other topics", topics.len() - MAX_TOPICS)?;
+        }
+        Ok(())
+    }
+}
+
+/// Buffers points received from the event loop and writes them to a sink in batches.
+pub struct Writer {
+    /// Sink to write to, or `None` to only log the points.
+    pub sink: Option<Box<dyn PointSink>>,
+    pub precision: Precision,
+    pub batch_size: usize,
+    pub flush_interval: Duration,
+    /// Number of times a failed write is retried before its points are dropped.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubling for every further retry.
+    pub retry_delay: Duration,
+    /// Where to keep points that could not be written instead of dropping them, by target.
+    pub spools: BTreeMap<WriteTarget, Spool>,
+    pub health: Arc<Health>,
+    /// Index of the sink to report its health under.
+    pub index: usize,
+    pub metrics: Arc<Metrics>,
+}
+
+/// Whether a failed write may succeed when sent again.
+fn is_transient(e: &WriteError) -> bool {
+    !matches!(
+        e.inner.inner,
+        ErrorKind::SyntaxError(_)
+            | ErrorKind::InvalidCredentials(_)
+            | ErrorKind::DataBaseDoesNotExist(_)
+    )
+}
+
+impl Writer {
+    /// Precision of the timestamps of points for `target`.
+    fn precision(&self, target: &WriteTarget) -> Precision {
+        target.precision.unwrap_or(self.precision)
+    }
+
+    /// Write points, each with the target to write it to, until `points` is closed, then flush
+    /// whatever is left.
+    pub async fn run(self, mut points: mpsc::Receiver<QueuedPoint>) {
+        let mut buffer = Buffer::new();
+        let mut flush_timer = tokio::time::interval(self.flush_interval);
+        flush_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                point = points.recv() => match point {
+                    Some(QueuedPoint { target, topic, point }) => {
+                        buffer.entry(target).or_default().push((topic, point));
+                        if buffer.values().map(Vec::len).sum::<usize>() >= self.batch_size {
+                            self.flush(&mut buffer).await;
+                        }
+                    }
+                    None => {
+                        self.flush(&mut buffer).await;
+                        return;
+                    }
+                },
+                _ = flush_timer.tick() => self.flush(&mut buffer).await,
+            }
+        }
+    }
+
+    /// Write all buffered points to the sink, in a single request per target.
+    async fn flush(&self, buffer: &mut Buffer) {
+        for target in self.spools.keys() {
+            buffer.entry(target.clone()).or_default();
+        }
+        for (target, points) in std::mem::take(buffer) {
+            self.flush_target(&target, points).await;
+        }
+    }
+
+    /// Write `points` to `target` in a single request, or only log them without a client.
+    ///
+    /// Points that were spooled earlier for the target are replayed first, so points are written
+    /// in the order they were received.
+    async fn flush_target(&self, target: &WriteTarget, points: Vec<(String, Point<'static>)>) {
+        let spool = self.spools.get(target);
+        if points.is_empty() && spool.is_none_or(Spool::is_empty) {
+            return;
+        }
+
+        let Some(sink) = &self.sink else {
+            for (topic, point) in points {
+                log::info!("Dry run, not writing {:?} from {}", point, topic);
+            }
+            return;
+        };
+
+        let lines = line_protocol::serialize(points.iter().map(|(_, point)| point));
+        let count = points.len();
+
+        if let Some(spool) = spool {
+            if !spool.is_empty() && !self.replay(sink.as_ref(), target, spool).await {
+                if count > 0 {
+                    self.spill(spool, &lines, count);
+                }
+                return;
+            }
+        }
+        if count == 0 {
+            return;
+        }
+
+        if let Err(e) = self.write(sink.as_ref(), target, &lines, count).await {
+            match spool {
+                Some(spool) if is_transient(&e) => self.spill(spool, &lines, count),
+                _ => log::error!(
+                    "Failed to write {} to {}, dropping them: {}",
+                    Batch {
+                        target,
+                        points: &points
+                    },
+                    sink.name(),
+                    e
+                ),
+            }
+        }
+    }
+
+    /// Write `count` points worth of `lines` to `target`, retrying transient failures.
+    async fn write(
+        &self,
+        sink: &dyn PointSink,
+        target: &WriteTarget,
+        lines: &str,
+        count: usize,
+    ) -> Result<(), WriteError> {
+        let mut delay = self.retry_delay;
+        for attempt in 0.. {
+            let started = Instant::now();
+            let result = sink
+                .write_points(target, lines, self.precision(target))
+                .await;
+            self.health.set_sink_ok(self.index, result.is_ok());
+
+            match result {
+                Ok(()) => {
+                    log::debug!("Wrote {} points to {}", count, sink.name());
+                    self.metrics.points_written(count, started.elapsed());
+                    return Ok(());
+                }
+                Err(e) if attempt < self.max_retries && is_transient(&e) => {
+                    self.metrics.write_error();
+                    // Rate limited or overloaded servers say when to come back.
+                    if let Some(retry_after) = e.retry_after {
+                        log::warn!(
+                            "Failed to write {} points to {}: {}, retry {}/{} in {:?} as \
+                             requested by Retry-After",
+                            count,
+                            sink.name(),
+                            e,
+                            attempt + 1,
+                            self.max_retries,
+                            retry_after
+                        );
+                        tokio::time::sleep(retry_after).await;
+                        continue;
+                    }
+                    log::warn!(
+                        "Failed to write {} points to {}: {}, retry {}/{} in {:?}",
+                        count,
+                        sink.name(),
+                        e,
+                        attempt + 1,
+                        self.max_retries,
+                        delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => {
+                    self.metrics.write_error();
+                    return Err(e);
+                }
+            }
+        }
+        unreachable!()
+    }
+
+    /// Keep points that could not be written in the spool.
+    fn spill(&self, spool: &Spool, lines: &str, count: usize) {
+        match spool.append(lines) {
+            Ok(()) => log::warn!("Spooled {} points until InfluxDB is reachable", count),
+            Err(e) => log::error!("Failed to spool {} points, dropping them: {}", count, e),
+        }
+    }
+
+    /// Once the sink responds to a ping again, write all spooled points in batches.
+    ///
+    /// Returns whether the spool was emptied; if not, the points that were not written yet are
+    /// kept.
+    async fn replay(&self, sink: &dyn PointSink, target: &WriteTarget, spool: &Spool) -> bool {
+        if !sink.ping().await {
+            return false;
+        }
+
+        let spooled = match spool.read() {
+            Ok(spooled) => spooled,
+            Err(e) => {
+                log::error!("Failed to read spooled points: {}", e);
+                return false;
+            }
+        };
+
+        let lines: Vec<&str> = spooled.lines().collect();
+        let mut written = 0;
+        for batch in lines.chunks(self.batch_size.max(1)) {
+            let mut batch_lines = batch.join("\n");
+            batch_lines.push('\n');
+
+            let started = Instant::now();
+            match sink
+                .write_points(target, &batch_lines, self.precision(target))
+                .await
+            {
+                Ok(()) => self.metrics.points_written(batch.len(), started.elapsed()),
+                Err(e) if is_transient(&e) => {
+                    self.metrics.write_error();
+                    self.health.set_sink_ok(self.index, false);
+                    log::warn!(
+                        "Failed to replay spooled points: {}, {} left in the spool",
+                        e,
+                        lines.len() - written
+                    );
+
+                    let mut remaining = lines[written..].join("\n");
+                    remaining.push('\n');
+                    if let Err(e) = spool.replace(&remaining) {
+                        log::error!("Failed to update spool: {}", e);
+                    }
+                    return false;
+                }
+                Err(e) => {
+                    self.metrics.write_error();
+                    log::error!(
+                        "Failed to replay {} spooled points for {}, dropping them: {}",
+                        batch.len(),
+                        target,
+                        e
+                    );
+                }
+            }
+            written += batch.len();
+        }
+
+        log::info!("Replayed {} spooled points", lines.len());
+        if let Err(e) = spool.replace("") {
+            log::error!("Failed to clear spool: {}", e);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Mutex,
+        },
+    };
+
+    use super::*;
+    use crate::sink::BoxFuture;
+
+    /// Records the batches written to it, failing while it is down.
+    #[derive(Default)]
+    struct TestSink {
+        down: AtomicBool,
+        written: Mutex<Vec<String>>,
+    }
+
+    impl PointSink for Arc<TestSink> {
+        fn write_points<'a>(
+            &'a self,
+            _target: &'a WriteTarget,
+            lines: &'a str,
+            _precision: Precision,
+        ) -> BoxFuture<'a, Result<(), WriteError>> {
+            Box::pin(async move {
+                if self.down.load(Ordering::Relaxed) {
+                    return Err(WriteError::from(influxdb_rs::Error {
+                        inner: ErrorKind::Communication("down".to_owned()),
+                    }));
+                }
+                self.written.lock().unwrap().push(lines.to_owned());
+                Ok(())
+            })
+        }
+
+        fn ping(&self) -> BoxFuture<'_, bool> {
+            Box::pin(async { !self.down.load(Ordering::Relaxed) })
+        }
+
+        fn name(&self) -> &str {
+            "test"
+        }
+    }
+
+    fn point(value: i64) -> (String, Point<'static>) {
+        let point = Point::new("m")
+            .add_field("v", crate::point::Value::Integer(value))
+            .add_timestamp(value);
+        ("sensors/a".to_owned(), point)
+    }
+
+    #[test]
+    fn full_queue_does_not_hold_up_the_others() {
+        let metrics = Arc::new(Metrics::default());
+        let mut queues = Queues::new(metrics.clone());
+        let (slow_tx, mut slow_rx) = mpsc::channel(1);
+        let (fast_tx, mut fast_rx) = mpsc::channel(10);
+        queues.add("slow".to_owned(), slow_tx);
+        queues.add("fast".to_owned(), fast_tx);
+
+        for value in 1..=3 {
+            let (topic, point) = point(value);
+            queues.send(QueuedPoint {
+                target: WriteTarget::default(),
+                topic,
+                point,
+            });
+        }
+
+        let timestamps = |rx: &mut mpsc::Receiver<QueuedPoint>| {
+            std::iter::from_fn(|| rx.try_recv().ok())
+                .map(|queued| queued.point.timestamp.unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(timestamps(&mut slow_rx), [1]);
+        assert_eq!(timestamps(&mut fast_rx), [1, 2, 3]);
+        assert!(metrics
+            .render()
+            .contains("mqtt2influxdb_points_dropped_total 2\n"));
+    }
+
+    #[tokio::test]
+    async fn spooled_points_are_replayed_in_order() {
+        let dir = std::env::temp_dir().join(format!("mqtt2influxdb-writer-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let target = WriteTarget::default();
+        let sink = Arc::new(TestSink::default());
+        let writer = Writer {
+            sink: Some(Box::new(sink.clone())),
+            precision: Precision::Nanoseconds,
+            batch_size: 10,
+            flush_interval: Duration::from_secs(1),
+            max_retries: 0,
+            retry_delay: Duration::ZERO,
+            spools: BTreeMap::from([(target.clone(), Spool::open(&dir, &target, 1024).unwrap())]),
+            health: Arc::new(Health::new(1)),
+            index: 0,
+            metrics: Arc::new(Metrics::default()),
+        };
+
+        sink.down.store(true, Ordering::Relaxed);
+        writer.flush_target(&target, vec![point(1), point(2)]).await;
+        writer.flush_target(&target, vec![point(3)]).await;
+        assert!(sink.written.lock().unwrap().is_empty());
+        assert_eq!(
+            writer.spools[&target].read().unwrap(),
+            "m v=1i 1\nm v=2i 2\nm v=3i 3\n"
+        );
+
+        sink.down.store(false, Ordering::Relaxed);
+        writer.flush_target(&target, vec![point(4)]).await;
+        assert_eq!(
+            *sink.written.lock().unwrap(),
+            ["m v=1i 1\nm v=2i 2\nm v=3i 3\n", "m v=4i 4\n"]
+        );
+        assert!(writer.spools[&target].is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}